@@ -1,26 +1,119 @@
 use crate::config::CONFIG;
+use crate::project::{NamedQuery, Project};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use beam_lib::{AppId, MsgId, RawString, TaskRequest};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
 
-pub fn create_beam_task(target_sites: Vec<String>) -> TaskRequest<RawString> {
-    let target_app = &CONFIG.target_app;
-    let id = MsgId::new();
-    let proxy_id = &CONFIG.beam_app_id_long.proxy_id();
-    let query_encoded: String = BASE64.encode(&CONFIG.query);
-    let broker_id = proxy_id
+const RESERVED_METADATA: [&str; 2] = ["project", "query"]; // set by Prism for every task
+
+/// Parameters of the tasks sent to the sites
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskParams {
+    pub ttl: Duration,
+    pub retry_backoff: Duration,
+    pub max_tries: u32,
+    pub metadata: Map<String, Value>, // added to the metadata of the task, Focus deployments may route on it
+}
+
+/// How the results of the tasks are retrieved from Beam
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ResultMode {
+    /// Server-Sent Events, falling back to long polling for a task if the stream fails
+    Auto,
+    /// Server-Sent Events only
+    Sse,
+    /// Long polling of the results as JSON, for networks where proxies buffer or strip Server-Sent Events
+    Poll,
+}
+
+/// Task parameters overridden for a project or a site in the projects file
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TaskOverrides {
+    /// In seconds
+    #[serde(default)]
+    ttl: Option<u64>,
+    /// In milliseconds
+    #[serde(default)]
+    retry_backoff: Option<u64>,
+    #[serde(default)]
+    max_tries: Option<u32>,
+    /// Merged with the metadata it overrides
+    #[serde(default)]
+    metadata: Map<String, Value>,
+}
+
+impl TaskParams {
+    pub fn with(&self, overrides: &TaskOverrides) -> TaskParams {
+        let mut metadata = self.metadata.clone();
+        metadata.extend(overrides.metadata.clone());
+        TaskParams {
+            ttl: overrides.ttl.map_or(self.ttl, Duration::from_secs),
+            retry_backoff: overrides
+                .retry_backoff
+                .map_or(self.retry_backoff, Duration::from_millis),
+            max_tries: overrides.max_tries.unwrap_or(self.max_tries),
+            metadata,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl.is_zero() {
+            return Err("task TTL must be positive".into());
+        }
+        if self.max_tries == 0 {
+            return Err("tasks must be tried at least once".into());
+        }
+        if self.retry_backoff * (self.max_tries - 1) >= self.ttl {
+            return Err(format!(
+                "tasks would expire before being tried {} times with a backoff of {}ms, raise the TTL of {}s",
+                self.max_tries,
+                self.retry_backoff.as_millis(),
+                self.ttl.as_secs()
+            ));
+        }
+        if let Some(key) = RESERVED_METADATA
+            .iter()
+            .find(|key| self.metadata.contains_key(**key))
+        {
+            return Err(format!("metadata key {key} is set by Prism"));
+        }
+        Ok(())
+    }
+}
+
+/// The broker this application's proxy is connected to, the sites are proxies of the same broker
+pub fn broker_id() -> String {
+    let proxy_id = CONFIG.beam_app_id_long.proxy_id();
+    proxy_id
         .as_ref()
         .split_once('.')
         .expect("Invalid beam id in config")
-        .1;
+        .1
+        .to_string()
+}
+
+pub fn create_beam_task(
+    project: &Project,
+    query: &NamedQuery,
+    params: &TaskParams,
+    target_sites: Vec<String>,
+) -> TaskRequest<RawString> {
+    let target_app = &project.target_app;
+    let id = MsgId::new();
+    let query_encoded: String = BASE64.encode(&query.body);
+    let broker_id = broker_id();
     let to = target_sites
         .iter()
         .map(|site| AppId::new_unchecked(format!("{target_app}.{site}.{broker_id}")))
         .collect();
     let metadata = {
-        serde_json::json!({
-            "project": &CONFIG.project,
-            "execute": false
-        })
+        let mut metadata = params.metadata.clone();
+        metadata.insert("project".into(), project.name.clone().into());
+        metadata.insert("query".into(), query.name.clone().into());
+        Value::Object(metadata)
     };
     TaskRequest {
         id,
@@ -29,9 +122,66 @@ pub fn create_beam_task(target_sites: Vec<String>) -> TaskRequest<RawString> {
         metadata,
         body: query_encoded.into(),
         failure_strategy: beam_lib::FailureStrategy::Retry {
-            backoff_millisecs: 1000,
-            max_tries: 5,
+            backoff_millisecs: params.retry_backoff.as_millis() as usize,
+            max_tries: params.max_tries as usize,
         },
-        ttl: "360s".to_string(),
+        ttl: format!("{}s", params.ttl.as_secs()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_config::{project, task_params};
+
+    #[test]
+    fn test_task_overrides() {
+        let params = TaskParams {
+            metadata: serde_json::from_str(r#"{"execute": false}"#).unwrap(),
+            ..task_params()
+        };
+        let overrides: TaskOverrides =
+            serde_json::from_str(r#"{"ttl": 3600, "metadata": {"route": "slow"}}"#).unwrap();
+
+        let overridden = params.with(&overrides);
+
+        assert_eq!(Duration::from_secs(3600), overridden.ttl);
+        assert_eq!(5, overridden.max_tries);
+        pretty_assertions::assert_eq!(
+            r#"{"execute":false,"route":"slow"}"#,
+            serde_json::to_string(&overridden.metadata).unwrap()
+        );
+        assert!(overridden.validate().is_ok());
+
+        let too_short: TaskOverrides = serde_json::from_str(r#"{"ttl": 2}"#).unwrap();
+        assert!(params.with(&too_short).validate().is_err());
+        let reserved: TaskOverrides =
+            serde_json::from_str(r#"{"metadata": {"project": "other"}}"#).unwrap();
+        assert!(params.with(&reserved).validate().is_err());
+    }
+
+    #[test]
+    fn test_create_beam_task() {
+        let params = TaskParams {
+            metadata: serde_json::from_str(r#"{"execute": false}"#).unwrap(),
+            ..task_params()
+        };
+        let project = project(&[("core", "{}")]);
+        let query = &project.queries["core"];
+
+        let task = create_beam_task(&project, query, &params, vec!["proxy1".into()]);
+
+        pretty_assertions::assert_eq!(
+            r#"{"execute":false,"project":"bbmri","query":"core"}"#,
+            serde_json::to_string(&task.metadata).unwrap()
+        );
+        assert_eq!(
+            vec!["focus.proxy1.broker"],
+            task.to
+                .iter()
+                .map(|app_id| app_id.as_ref())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("360s", task.ttl);
     }
 }
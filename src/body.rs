@@ -0,0 +1,201 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::PrismError;
+
+const LANGUAGES: [&str; 1] = ["ast"]; // languages Focus in the Bridgeheads understands
+
+/// Query body as sent to Focus, the payload is the base64 encoded query
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QueryBody {
+    pub lang: String,
+    pub payload: String,
+}
+
+/// Query in Focus' AST format
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Ast {
+    pub ast: Operation,
+    pub id: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Operation {
+    pub operand: Operand,
+    pub children: Vec<Child>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operand {
+    And,
+    Or,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Child {
+    Operation(Operation),
+    Condition(Condition),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub key: String,
+    #[serde(rename = "type")]
+    pub type_: ConditionType,
+    pub system: String,
+    pub value: ConditionValue,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ConditionType {
+    Equals,
+    NotEquals,
+    Contains,
+    GreaterThan,
+    LowerThan,
+    Between,
+    In,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ConditionValue {
+    String(String),
+    StringArray(Vec<String>),
+    Boolean(bool),
+    Number(f64),
+    NumRange { min: f64, max: f64 },
+    DateRange { min: String, max: String },
+}
+
+/// Checks that the body is what Focus expects and decodes the AST in its payload
+pub fn decode_body(body: &str) -> Result<Ast, PrismError> {
+    let body: QueryBody = serde_json::from_str(body).map_err(|e| {
+        PrismError::InvalidBody(format!("not a JSON object with lang and payload, {e}"))
+    })?;
+    if !LANGUAGES.contains(&body.lang.as_str()) {
+        return Err(PrismError::InvalidBody(format!(
+            "lang {:?} is not understood by Focus, use one of {:?}",
+            body.lang, LANGUAGES
+        )));
+    }
+    let payload = BASE64
+        .decode(body.payload.trim())
+        .map_err(|e| PrismError::InvalidBody(format!("payload is not valid base64, {e}")))?;
+    serde_json::from_slice(&payload).map_err(|e| {
+        PrismError::InvalidBody(format!(
+            "payload doesn't decode to an AST with an operation and an id, {e}"
+        ))
+    })
+}
+
+/// Reads a body file, either the body as sent to Focus or the AST in readable JSON or YAML, which is then encoded.
+/// Returns the body as sent to Focus together with the AST.
+pub fn load_body(text: &str) -> Result<(String, Ast), PrismError> {
+    let value: serde_yaml::Value = serde_yaml::from_str(text)
+        .map_err(|e| PrismError::InvalidBody(format!("neither JSON nor YAML, {e}")))?;
+    if value.get("lang").is_some() || value.get("payload").is_some() {
+        let ast = decode_body(text)?;
+        return Ok((text.to_string(), ast));
+    }
+    let ast: Ast = serde_yaml::from_value(value).map_err(|e| {
+        PrismError::InvalidBody(format!(
+            "neither a body with lang and payload nor an AST with an operation and an id, {e}"
+        ))
+    })?;
+    Ok((encode_ast(&ast), ast))
+}
+
+/// The body with the AST as sent to Focus
+pub fn encode_ast(ast: &Ast) -> String {
+    let body = QueryBody {
+        lang: "ast".into(),
+        payload: BASE64.encode(serde_json::to_string(ast).expect("Failed to serialize JSON")),
+    };
+    serde_json::to_string(&body).expect("Failed to serialize JSON")
+}
+
+pub fn pretty_ast(ast: &Ast) -> String {
+    serde_json::to_string_pretty(ast).expect("Failed to serialize JSON")
+}
+
+pub fn yaml_ast(ast: &Ast) -> String {
+    serde_yaml::to_string(ast).expect("Failed to serialize YAML")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BODY_BBMRI: &str = include_str!("../resources/body_bbmri.json");
+
+    fn body(lang: &str, payload: &str) -> String {
+        format!(
+            r#"{{"lang":"{lang}","payload":"{}"}}"#,
+            BASE64.encode(payload)
+        )
+    }
+
+    #[test]
+    fn test_decode_resources_body() {
+        let ast = decode_body(BODY_BBMRI).unwrap();
+        assert_eq!(Operand::Or, ast.ast.operand);
+    }
+
+    #[test]
+    fn test_decode_conditions() {
+        let ast = decode_body(&body(
+            "ast",
+            r#"{"ast":{"operand":"AND","children":[{"key":"gender","type":"EQUALS","system":"","value":"male"},{"operand":"OR","children":[{"key":"age","type":"BETWEEN","system":"","value":{"min":18,"max":65}}]}]},"id":"1"}"#,
+        ))
+        .unwrap();
+        assert_eq!(2, ast.ast.children.len());
+    }
+
+    #[test]
+    fn test_readable_ast_roundtrip() {
+        let readable = r#"
+ast:
+  operand: AND
+  children:
+    - key: gender
+      type: EQUALS
+      system: ""
+      value: male
+id: "1"
+"#;
+        let (body, ast) = load_body(readable).unwrap();
+        pretty_assertions::assert_eq!(ast, decode_body(&body).unwrap());
+        pretty_assertions::assert_eq!(ast, load_body(&pretty_ast(&ast)).unwrap().1);
+        pretty_assertions::assert_eq!(ast, load_body(&yaml_ast(&ast)).unwrap().1);
+        // bodies as sent to Focus are taken as they are
+        assert_eq!(BODY_BBMRI, load_body(BODY_BBMRI).unwrap().0);
+    }
+
+    #[test]
+    fn test_invalid_bodies() {
+        let errors = [
+            ("{", "not a JSON object"),
+            (&body("cql", "{}"), "lang \"cql\""),
+            (
+                r#"{"lang":"ast","payload":"not base64!"}"#,
+                "not valid base64",
+            ),
+            (
+                &body("ast", r#"{"ast":{"operand":"XOR","children":[]},"id":"1"}"#),
+                "doesn't decode to an AST",
+            ),
+        ];
+        for (body, expected) in errors {
+            let error = decode_body(body).unwrap_err().to_string();
+            assert!(error.contains(expected), "{error}");
+        }
+    }
+}
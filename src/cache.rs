@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{criteria::Stratifiers, errors::PrismError};
+
+pub type Site = String;
+pub type Created = std::time::SystemTime; //epoch
+
+pub const CRITERIACACHE_TTL: Duration = Duration::from_secs(7200); //cached criteria expire after 2h, unless the query sets its own TTL
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CriteriaCache {
+    pub cache: HashMap<Site, (Stratifiers, Created)>,
+    #[serde(default)]
+    pub fingerprint: Option<String>, // of the query the criteria were computed with, set when the snapshot is written
+}
+
+/// Criteria caches of all the projects, one for each of their queries, as persisted in the cache file
+pub type CacheSnapshot = BTreeMap<String, BTreeMap<String, CriteriaCache>>;
+
+/// Restores the caches from a snapshot written by [`save_snapshot`].
+/// A missing or unreadable snapshot results in empty caches, Prism then simply queries all the sites again.
+pub fn load_snapshot(path: &Path) -> CacheSnapshot {
+    let snapshot = match fs::read(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!(
+                "No cache snapshot at {}, starting with an empty cache",
+                path.display()
+            );
+            return CacheSnapshot::new();
+        }
+        Err(e) => {
+            warn!(
+                "Cache snapshot {} can't be read, starting with an empty cache: {e}",
+                path.display()
+            );
+            return CacheSnapshot::new();
+        }
+    };
+    match serde_json::from_slice::<CacheSnapshot>(&snapshot) {
+        Ok(snapshot) => {
+            for (project, criteria_caches) in &snapshot {
+                for (query, criteria_cache) in criteria_caches {
+                    info!(
+                        "Restored cached criteria for {} sites of project {} and query {} from {}",
+                        criteria_cache.cache.len(),
+                        project,
+                        query,
+                        path.display()
+                    );
+                }
+            }
+            snapshot
+        }
+        Err(e) => {
+            warn!(
+                "Cache snapshot {} is corrupt, starting with an empty cache: {e}",
+                path.display()
+            );
+            CacheSnapshot::new()
+        }
+    }
+}
+
+/// Writes the caches including the original timestamps, so that restored entries expire as if Prism had never been restarted.
+/// The snapshot is written to a temporary file first and then renamed, so a crash while writing never leaves a truncated snapshot behind.
+pub fn save_snapshot(snapshot: &CacheSnapshot, path: &Path) -> Result<(), PrismError> {
+    let serialized = serde_json::to_vec(snapshot).map_err(PrismError::SerializationError)?;
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serialized)
+        .and_then(|()| fs::rename(&tmp_path, path))
+        .map_err(|e| PrismError::SnapshotError(format!("{}: {e}", path.display())))
+}
+
+impl CriteriaCache {
+    pub fn is_expired(created: &Created, ttl: Duration) -> bool {
+        SystemTime::now()
+            .duration_since(*created)
+            .unwrap_or_default()
+            >= ttl
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::criteria::Criteria;
+
+    #[test]
+    fn test_snapshot_roundtrip_keeps_timestamps() {
+        let criteria: Criteria = [("female".into(), 10), ("male".into(), 20)].into();
+        let stratifiers: Stratifiers = [("gender".into(), criteria)].into();
+        let created = SystemTime::now() - Duration::from_secs(3600);
+
+        let mut criteria_cache = CriteriaCache::default();
+        criteria_cache
+            .cache
+            .insert("proxy1".into(), (stratifiers.clone(), created));
+        let snapshot: CacheSnapshot =
+            [("bbmri".into(), [("default".into(), criteria_cache)].into())].into();
+
+        let path = std::env::temp_dir().join(format!("prism_cache_{}.json", std::process::id()));
+        save_snapshot(&snapshot, &path).expect("Snapshot can't be written");
+        let restored = load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+
+        let (restored_stratifiers, restored_created) =
+            restored["bbmri"]["default"].cache.get("proxy1").unwrap();
+        pretty_assertions::assert_eq!(&stratifiers, restored_stratifiers);
+        assert_eq!(&created, restored_created);
+        assert!(!CriteriaCache::is_expired(
+            restored_created,
+            CRITERIACACHE_TTL
+        ));
+    }
+
+    #[test]
+    fn test_missing_snapshot_gives_empty_cache() {
+        let snapshot = load_snapshot(Path::new("/nonexistent/prism_cache.json"));
+        assert!(snapshot.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::cache::Site;
+
+const RECENT_CHUNKS: usize = 50; // kept for each query, older ones are forgotten
+
+static NEXT_CHUNK: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_chunk_id() -> u64 {
+    NEXT_CHUNK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Why a site isn't queried again, the chunk including it is waiting to be posted or its results are awaited
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InFlight {
+    pub chunk: u64,
+    pub expires: Option<Instant>, // none while the chunk waits for a free slot
+}
+
+impl InFlight {
+    pub fn is_active(&self) -> bool {
+        self.expires.is_none_or(|expires| expires > Instant::now())
+    }
+}
+
+/// Part of the sites queried together, sent in one task
+#[derive(Serialize, Debug, Clone)]
+pub struct ChunkStatus {
+    id: u64,
+    sites: Vec<Site>,
+    queued_at: String, // RFC 3339
+    #[serde(flatten)]
+    state: ChunkState,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ChunkState {
+    Waiting,
+    Collecting {
+        task: String,
+    },
+    Done {
+        task: String,
+        answered: Vec<Site>,
+        missing: Vec<Site>,   // failed or timed out
+        timed_out: Vec<Site>, // didn't answer before the collection deadline
+    },
+    Failed {
+        error: String, // the task couldn't be posted
+    },
+}
+
+pub type Chunks = VecDeque<ChunkStatus>; // most recent last
+
+pub fn record_chunk(chunks: &mut Chunks, id: u64, sites: Vec<Site>) {
+    if chunks.len() == RECENT_CHUNKS {
+        chunks.pop_front();
+    }
+    chunks.push_back(ChunkStatus {
+        id,
+        sites,
+        queued_at: Utc::now().to_rfc3339(),
+        state: ChunkState::Waiting,
+    });
+}
+
+pub fn set_chunk_state(chunks: &mut Chunks, id: u64, state: ChunkState) {
+    if let Some(chunk) = chunks.iter_mut().rev().find(|chunk| chunk.id == id) {
+        chunk.state = state;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent_chunks() {
+        let mut chunks = Chunks::new();
+        for id in 0..RECENT_CHUNKS as u64 + 2 {
+            record_chunk(&mut chunks, id, vec![format!("site{id}")]);
+        }
+        assert_eq!(RECENT_CHUNKS, chunks.len());
+        assert_eq!(2, chunks[0].id);
+
+        set_chunk_state(
+            &mut chunks,
+            3,
+            ChunkState::Done {
+                task: "task".into(),
+                answered: vec!["site3".into()],
+                missing: vec![],
+                timed_out: vec![],
+            },
+        );
+        let status = serde_json::to_value(&chunks[1]).unwrap();
+        assert_eq!("done", status["state"]);
+        assert_eq!("site3", status["answered"][0]);
+        assert_eq!(
+            "waiting",
+            serde_json::to_value(&chunks[0]).unwrap()["state"]
+        );
+    }
+}
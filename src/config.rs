@@ -1,31 +1,46 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::sync::Arc;
 
 use beam_lib::AppId;
 use clap::Parser;
+#[cfg(not(test))]
 use once_cell::sync::Lazy;
-use tracing::{debug, info};
+#[cfg(not(test))]
+use tracing::debug;
+use tracing::info;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use reqwest::Url;
-use tower_http::cors::AllowOrigin;
-
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use reqwest::{header::HeaderValue, Url};
+use serde_json::{Map, Value};
 
+use crate::beam::{ResultMode, TaskOverrides, TaskParams};
 use crate::errors::PrismError;
+use crate::obfuscation::{Obfuscation, ObfuscationMethod};
+use crate::outcome::Backoff;
+use crate::project::{load_project_definitions, Project, ProjectDefinition, Projects};
+use crate::suppression::SuppressionMode;
 
+#[cfg(not(test))]
 pub(crate) static CONFIG: Lazy<Config> = Lazy::new(|| {
     debug!("Loading config");
-    Config::load().unwrap_or_else(|e| {
+    Config::from_args(std::env::args_os()).unwrap_or_else(|e| {
         eprintln!("Unable to start as there was an error reading the config:\n{}\n\nTerminating -- please double-check your startup parameters with --help and refer to the documentation.", e);
         std::process::exit(1);
     })
 });
 
-const CLAP_FOOTER: &str =
-    "For updates and detailed usage instructions, visit https://github.com/samply/prism";
+// tests are run with the arguments of the test harness, they get their configuration from the test module
+#[cfg(test)]
+pub(crate) use crate::test_config::CONFIG;
+
+pub(crate) const CLAP_FOOTER: &str =
+    "Run prism validate --help for checking query body files, and prism body --help for converting them from and to a readable AST.\n\nFor updates and detailed usage instructions, visit https://github.com/samply/prism";
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(
     name("🏳️‍🌈⃤  Prism"),
     version,
@@ -45,25 +60,153 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     api_key: String,
 
-    /// Comma separated list of sites to initially query
+    /// Comma separated list of sites to initially query, for the project given by --project
     #[clap(long, env, value_parser, value_delimiter = ',')]
     sites: Vec<String>,
 
-    /// Where to allow cross-origin resourse sharing from
+    /// URL listing the proxies connected to the Beam broker, e.g. https://broker.samply.de/v1/health/proxies, their sites are queried in addition to the configured ones for the project given by --project, or the projects that enable discovery in the projects file
+    #[clap(long, env, value_parser)]
+    discovery_url: Option<Url>,
+
+    /// API key sent as the password of basic authentication when listing the proxies, such as the monitoring API key of the Beam broker
+    #[clap(long, env, value_parser)]
+    discovery_api_key: Option<String>,
+
+    /// How often the sites are discovered again, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "300")]
+    discovery_interval: u64,
+
+    /// Comma separated list of the only sites that are used when discovered, all discovered sites are used if not set
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    discovery_allow: Vec<String>,
+
+    /// Comma separated list of sites that aren't used when discovered, e.g. the site this application runs at
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    discovery_deny: Vec<String>,
+
+    /// Where to allow cross-origin resourse sharing from, any or a comma separated list of origins
     #[clap(long, env, value_parser = parse_cors)]
-    pub cors_origin: AllowOrigin,
+    pub cors_origin: CorsOrigins,
+
+    /// File with the origins to allow cross-origin resource sharing from, in the same format as --cors-origin, overrides it and is reloaded like the projects file
+    #[clap(long, env, value_parser)]
+    cors_origin_file: Option<PathBuf>,
 
-    /// Project name
+    /// How often the projects, body, hierarchy, mapping and CORS origin files are checked for changes, in seconds, 0 to only reload them on SIGHUP
+    #[clap(long, env, value_parser, default_value = "30")]
+    reload_interval: u64,
+
+    /// Project name, with a projects file the project served at /criteria
     #[clap(long, env)]
-    pub project: String,
+    pub project: Option<String>,
+
+    /// File defining several projects served from this instance, each with its own sites, query and cache
+    #[clap(long, env, value_parser)]
+    projects_file: Option<PathBuf>,
 
     /// The socket address this server will bind to
     #[clap(long, env, default_value = "0.0.0.0:8080")]
     pub bind_addr: SocketAddr,
 
-    /// Target_application_name
+    /// The socket address the operator endpoints /sites, /tasks, /quarantine and /metrics are served at, it shouldn't be reachable by Lens or from the internet
+    #[clap(long, env, default_value = "127.0.0.1:8079")]
+    admin_bind_addr: SocketAddr,
+
+    /// Target_application_name, for projects that don't set their own
     #[clap(long, env, value_parser, default_value = "focus")]
     target_app: String,
+
+    /// File the criteria cache is persisted to and restored from at startup, if not set the cache is only kept in memory
+    #[clap(long, env, value_parser)]
+    cache_file: Option<PathBuf>,
+
+    /// How often the criteria cache is written to the cache file, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "300")]
+    cache_save_interval: u64,
+
+    /// How long to wait for more requests before sending a task for sites missing in the cache, in seconds
+    #[clap(long, env, value_parser, default_value = "2")]
+    query_debounce: u64,
+
+    /// Counts below this threshold are suppressed after adding them up, no counts are suppressed if not set
+    #[clap(long, env, value_parser)]
+    suppression_threshold: Option<u64>,
+
+    /// Whether suppressed counts are left out or replaced with "<threshold"
+    #[clap(long, env, value_enum, default_value = "drop")]
+    suppression_mode: SuppressionMode,
+
+    /// How counts are obfuscated after adding them up
+    #[clap(long, env, value_enum, default_value = "none")]
+    obfuscation: ObfuscationMethod,
+
+    /// Counts are rounded to the nearest multiple of this number if obfuscation is rounding
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "10")]
+    rounding_step: u64,
+
+    /// Privacy budget of the Laplace noise if obfuscation is laplace, smaller values add more noise
+    #[clap(long, env, value_parser, default_value = "1.0")]
+    laplace_epsilon: f64,
+
+    /// Secret the Laplace noise is derived from, required if obfuscation is laplace
+    #[clap(long, env, value_parser)]
+    noise_secret: Option<String>,
+
+    /// File defining parent codes of hierarchical code systems, their counts are rolled up from the children's, for the project given by --project
+    #[clap(long, env, value_parser)]
+    hierarchy_file: Option<PathBuf>,
+
+    /// Time to live of the tasks sent to the sites, in seconds
+    #[clap(long, env, value_parser, default_value = "360")]
+    task_ttl: u64,
+
+    /// How long Beam waits before retrying a failed task, in milliseconds
+    #[clap(long, env, value_parser, default_value = "1000")]
+    task_retry_backoff: u64,
+
+    /// How often Beam tries a task
+    #[clap(long, env, value_parser, default_value = "5")]
+    task_max_tries: u32,
+
+    /// JSON object added to the metadata of the tasks, e.g. for routing in Focus
+    #[clap(long, env, value_parser = parse_metadata, default_value = r#"{"execute": false}"#)]
+    task_metadata: Map<String, Value>,
+
+    /// Sites queried together are split into tasks to at most this many sites each, all in one task if not set
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    task_chunk_size: Option<u64>,
+
+    /// How many tasks are posted at the same time, further chunks wait for a free slot, results are collected for all the posted tasks at once
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "4")]
+    task_concurrency: u64,
+
+    /// How long to wait before querying a site again after it failed, doubled with every further failure, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "30")]
+    site_retry_backoff: u64,
+
+    /// Longest wait before querying a failing site again, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "3600")]
+    site_retry_backoff_max: u64,
+
+    /// How long sites rejected by Beam as invalid receivers aren't queried, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "3600")]
+    quarantine_duration: u64,
+
+    /// How long the results of a task are collected before sites that haven't answered are recorded as timed out, in seconds, the task's TTL if not set
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    result_deadline: Option<u64>,
+
+    /// How the results of the tasks are retrieved from Beam
+    #[clap(long, env, value_enum, default_value = "auto")]
+    result_mode: ResultMode,
+
+    /// How long a poll for results waits for the sites to answer if the results are polled, in seconds
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..), default_value = "30")]
+    result_poll_wait: u64,
+
+    /// File with rules renaming, merging or dropping stratifiers and their values, applied to the results of each site, for the project given by --project
+    #[clap(long, env, value_parser)]
+    mapping_file: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -71,44 +214,278 @@ pub(crate) struct Config {
     pub beam_proxy_url: Url,
     pub beam_app_id_long: AppId,
     pub api_key: String,
-    pub sites: Vec<String>,
-    pub cors_origin: AllowOrigin,
-    pub project: String,
+    pub cors_origin: CorsOrigins,
+    pub projects: Projects,
+    pub default_project: Option<String>, // served at /criteria
     pub bind_addr: SocketAddr,
-    pub query: String,
-    pub target_app: String,
+    pub admin_bind_addr: SocketAddr,
+    pub cache_file: Option<PathBuf>,
+    pub cache_save_interval: Duration,
+    pub query_debounce: Duration,
+    pub suppression_threshold: Option<u64>,
+    pub suppression_mode: SuppressionMode,
+    pub obfuscation: Obfuscation,
+    pub projects_file: Option<PathBuf>,
+    pub cors_origin_file: Option<PathBuf>,
+    pub reload_interval: Option<Duration>,
+    pub task_chunk_size: Option<usize>,
+    pub task_concurrency: usize,
+    pub site_retry_backoff: Backoff,
+    pub quarantine_duration: Duration,
+    pub result_deadline: Option<Duration>,
+    pub result_mode: ResultMode,
+    pub result_poll_wait: Duration,
+    pub discovery_url: Option<Url>,
+    pub discovery_api_key: Option<String>,
+    pub discovery_interval: Duration,
+    pub discovery_allow: Vec<String>,
+    pub discovery_deny: Vec<String>,
+    cli_args: CliArgs, // for reloading the projects and CORS origins
+}
+
+/// Origins allowed for cross-origin resource sharing
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+impl CorsOrigins {
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        match self {
+            CorsOrigins::Any => true,
+            CorsOrigins::List(origins) => origins.contains(origin),
+        }
+    }
 }
 
 impl Config {
-    fn load() -> Result<Self, PrismError> {
-        let cli_args = CliArgs::parse();
+    /// Reads the configuration from the command line arguments, the first being the name of the program
+    pub(crate) fn from_args<I, T>(args: I) -> Result<Self, PrismError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let cli_args = CliArgs::parse_from(args);
+        let reload_args = cli_args.clone();
         info!("Successfully read config and API keys from CLI and secrets files.");
+        let (projects, default_project) = load_projects(&cli_args)?;
+        let cors_origin = load_cors_origins(&cli_args)?;
         let config = Config {
             beam_proxy_url: cli_args.beam_proxy_url,
             beam_app_id_long: AppId::new_unchecked(cli_args.beam_app_id_long),
             api_key: cli_args.api_key,
-            sites: cli_args.sites,
-            cors_origin: cli_args.cors_origin,
-            project: cli_args.project,
+            cors_origin,
+            projects,
+            default_project,
             bind_addr: cli_args.bind_addr,
-            query: get_query(),
-            target_app: cli_args.target_app,
+            admin_bind_addr: cli_args.admin_bind_addr,
+            cache_file: cli_args.cache_file,
+            cache_save_interval: Duration::from_secs(cli_args.cache_save_interval),
+            query_debounce: Duration::from_secs(cli_args.query_debounce),
+            suppression_threshold: cli_args.suppression_threshold,
+            suppression_mode: cli_args.suppression_mode,
+            obfuscation: match cli_args.obfuscation {
+                ObfuscationMethod::None => Obfuscation::None,
+                ObfuscationMethod::Rounding => Obfuscation::Rounding {
+                    step: cli_args.rounding_step,
+                },
+                ObfuscationMethod::Laplace => {
+                    if !cli_args.laplace_epsilon.is_finite() || cli_args.laplace_epsilon <= 0.0 {
+                        return Err(PrismError::ConfigError(format!(
+                            "Laplace epsilon must be positive, but is {}",
+                            cli_args.laplace_epsilon
+                        )));
+                    }
+                    // a secret generated at startup would draw new noise for the same cached counts after every restart, averaging them would reveal the counts
+                    let secret = cli_args.noise_secret.ok_or_else(|| {
+                        PrismError::ConfigError(
+                            "--noise-secret must be set if obfuscation is laplace".into(),
+                        )
+                    })?;
+                    Obfuscation::Laplace {
+                        epsilon: cli_args.laplace_epsilon,
+                        secret,
+                    }
+                }
+            },
+            projects_file: cli_args.projects_file,
+            cors_origin_file: cli_args.cors_origin_file,
+            reload_interval: match cli_args.reload_interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            task_chunk_size: cli_args.task_chunk_size.map(|size| size as usize),
+            task_concurrency: cli_args.task_concurrency as usize,
+            site_retry_backoff: Backoff {
+                initial: Duration::from_secs(cli_args.site_retry_backoff),
+                max: Duration::from_secs(cli_args.site_retry_backoff_max),
+            },
+            quarantine_duration: Duration::from_secs(cli_args.quarantine_duration),
+            result_deadline: cli_args.result_deadline.map(Duration::from_secs),
+            result_mode: cli_args.result_mode,
+            result_poll_wait: Duration::from_secs(cli_args.result_poll_wait),
+            discovery_url: cli_args.discovery_url,
+            discovery_api_key: cli_args.discovery_api_key,
+            discovery_interval: Duration::from_secs(cli_args.discovery_interval),
+            discovery_allow: cli_args.discovery_allow,
+            discovery_deny: cli_args.discovery_deny,
+            cli_args: reload_args,
         };
         Ok(config)
     }
 }
 
-fn get_query() -> String {
-    let body_file_name = format!("resources/body_{}.json", CliArgs::parse().project);
+/// Reads the projects and CORS origins again, for reloading them without a restart
+pub fn reload() -> Result<(Projects, CorsOrigins), PrismError> {
+    let (projects, _) = load_projects(&CONFIG.cli_args)?;
+    let cors_origin = load_cors_origins(&CONFIG.cli_args)?;
+    Ok((projects, cors_origin))
+}
+
+fn load_cors_origins(cli_args: &CliArgs) -> Result<CorsOrigins, PrismError> {
+    let Some(cors_origin_file) = &cli_args.cors_origin_file else {
+        return Ok(cli_args.cors_origin.clone());
+    };
+    let cors_origin = fs::read_to_string(cors_origin_file).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "CORS origin file {} can't be read: {e}",
+            cors_origin_file.display()
+        ))
+    })?;
+    parse_cors(cors_origin.trim()).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "CORS origin file {} is invalid: {e}",
+            cors_origin_file.display()
+        ))
+    })
+}
+
+/// Projects come from the projects file, or else the single project given by --project, --sites, --hierarchy-file and --mapping-file
+fn load_projects(cli_args: &CliArgs) -> Result<(Projects, Option<String>), PrismError> {
+    let definitions = match &cli_args.projects_file {
+        Some(projects_file) => load_project_definitions(projects_file)?,
+        None => {
+            let name = cli_args.project.clone().ok_or_else(|| {
+                PrismError::ConfigError("Either --project or --projects-file must be given".into())
+            })?;
+            let definition = ProjectDefinition {
+                sites: cli_args.sites.clone(),
+                target_app: None,
+                body_file: None,
+                queries: BTreeMap::new(),
+                task: TaskOverrides::default(),
+                site_tasks: BTreeMap::new(),
+                hierarchy_file: cli_args.hierarchy_file.clone(),
+                mapping_file: cli_args.mapping_file.clone(),
+                discovery: true,
+            };
+            BTreeMap::from([(name, definition)])
+        }
+    };
+    if definitions.is_empty() {
+        return Err(PrismError::ConfigError("No projects configured".into()));
+    }
+
+    let default_project = match &cli_args.project {
+        Some(project) if !definitions.contains_key(project) => {
+            return Err(PrismError::ConfigError(format!(
+                "Project {project} is not defined in the projects file"
+            )));
+        }
+        Some(project) => Some(project.clone()),
+        None if definitions.len() == 1 => definitions.keys().next().cloned(),
+        None => None,
+    };
+
+    let task_params = TaskParams {
+        ttl: Duration::from_secs(cli_args.task_ttl),
+        retry_backoff: Duration::from_millis(cli_args.task_retry_backoff),
+        max_tries: cli_args.task_max_tries,
+        metadata: cli_args.task_metadata.clone(),
+    };
+    task_params
+        .validate()
+        .map_err(|e| PrismError::ConfigError(format!("Task parameters are invalid: {e}")))?;
 
-    fs::read_to_string(&body_file_name)
-        .unwrap_or_else(|_| panic!("File {} can't be read", &body_file_name))
+    let projects = definitions
+        .into_iter()
+        .map(|(name, definition)| {
+            Project::load(name.clone(), definition, &cli_args.target_app, &task_params)
+                .map(|project| (name, Arc::new(project)))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok((projects, default_project))
 }
 
-fn parse_cors(v: &str) -> Result<AllowOrigin, reqwest::header::InvalidHeaderValue> {
+fn parse_metadata(v: &str) -> Result<Map<String, Value>, serde_json::Error> {
+    serde_json::from_str(v)
+}
+
+fn parse_cors(v: &str) -> Result<CorsOrigins, reqwest::header::InvalidHeaderValue> {
     if v == "*" || v.to_lowercase() == "any" {
-        Ok(AllowOrigin::any())
+        Ok(CorsOrigins::Any)
     } else {
-        v.parse().map(AllowOrigin::exact)
+        v.split(',')
+            .map(|origin| origin.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(CorsOrigins::List)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REQUIRED_ARGS: [&str; 9] = [
+        "prism",
+        "--beam-proxy-url",
+        "http://localhost:8081",
+        "--beam-app-id-long",
+        "prism.proxy0.broker",
+        "--api-key",
+        "test",
+        "--cors-origin",
+        "any",
+    ];
+
+    fn config(args: &[&str]) -> Result<Config, PrismError> {
+        Config::from_args(REQUIRED_ARGS.iter().chain(args))
+    }
+
+    #[test]
+    fn test_noise_secret_required() {
+        assert!(config(&["--project", "bbmri", "--obfuscation", "laplace"]).is_err());
+        let config = config(&[
+            "--project",
+            "bbmri",
+            "--obfuscation",
+            "laplace",
+            "--noise-secret",
+            "s3cret",
+        ])
+        .unwrap();
+        assert!(matches!(
+            config.obfuscation,
+            Obfuscation::Laplace { ref secret, .. } if secret == "s3cret"
+        ));
+    }
+
+    #[test]
+    fn test_project_named_stream() {
+        let Err(PrismError::ConfigError(e)) = config(&["--project", "stream"]) else {
+            panic!("A project named stream was accepted");
+        };
+        assert_eq!("A project can't be named stream", e);
+    }
+
+    #[test]
+    fn test_cache_save_interval() {
+        // parsing the arguments exits on errors, as they are printed with the usage
+        let zero = ["--project", "bbmri", "--cache-save-interval", "0"];
+        assert!(CliArgs::try_parse_from(REQUIRED_ARGS.iter().chain(&zero)).is_err());
+        let config = config(&["--project", "bbmri", "--cache-save-interval", "60"]).unwrap();
+        assert_eq!(Duration::from_secs(60), config.cache_save_interval);
     }
 }
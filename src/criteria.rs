@@ -11,7 +11,7 @@ pub type CriteriaGroups = BTreeMap<String, Stratifiers>; //the entire structure
 // for example criteria "female", "male", "other", and "unknown" belong to the group "gender", and the group "gender" together with the group "age" belongs to the group of groups "patient"
 // 2025-06-06 refactored extraction to remove groups and add all the stratifiers into one BTreeMap, function preserved in case another project needs groups
 
-fn combine_maps(map1: Criteria, map2: Criteria) -> Criteria {
+pub fn combine_maps(map1: Criteria, map2: Criteria) -> Criteria {
     // here individual criteria are combined and their numbers added, for example 2 maps of gender criteria (see test)
     let mut combined_map = map1;
     for (key, value) in map2 {
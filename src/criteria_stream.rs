@@ -0,0 +1,100 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream, StreamExt as _};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::{
+    collect_criteria, gather_all_cached, requested_sites, suppression::PublishedStratifiers,
+    ProjectState, SharedState,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct StreamQuery {
+    sites: Option<String>, // comma separated, all the sites of the project if empty
+    query: Option<String>, // criteria of only this query of the project, all the queries merged if not given
+}
+
+/// Sends the criteria for the requested sites right away, like `/criteria`, and then again every time results for one of the sites are cached
+pub async fn handle_criteria_stream(
+    State(shared_state): State<SharedState>,
+    Query(query): Query<StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let project_state = shared_state.project(None)?;
+    stream_criteria(project_state, query).await
+}
+
+pub async fn handle_project_criteria_stream(
+    State(shared_state): State<SharedState>,
+    Path(project): Path<String>,
+    Query(query): Query<StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let project_state = shared_state.project(Some(&project))?;
+    stream_criteria(project_state, query).await
+}
+
+async fn stream_criteria(
+    project_state: &ProjectState,
+    query: StreamQuery,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let query_states = project_state.queries(query.query.as_deref())?;
+    let sites = requested_sites(
+        project_state,
+        query
+            .sites
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|site| !site.is_empty())
+            .map(String::from)
+            .collect(),
+    );
+
+    // subscribing before collecting the criteria so that no update between the two is lost
+    let cache_updates = project_state.cache_updates.subscribe();
+    let initial = collect_criteria(&query_states, sites.clone())
+        .await
+        .combined(&project_state.project().hierarchies);
+
+    let updates = stream::unfold(
+        (query_states, cache_updates, sites),
+        |(query_states, mut cache_updates, sites)| async move {
+            loop {
+                match cache_updates.recv().await {
+                    Ok(site) if sites.contains(&site) => {
+                        debug!("Results for site {} cached, sending criteria again", &site);
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Criteria stream missed {skipped} cache updates, sending criteria again");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+                let hierarchies = &query_states[0].project().hierarchies; // as currently configured, all the queries are of the same project
+                let stratifiers = gather_all_cached(&query_states, &sites)
+                    .await
+                    .combined(hierarchies);
+                return Some((stratifiers, (query_states, cache_updates, sites)));
+            }
+        },
+    );
+
+    let events = stream::once(async { initial })
+        .chain(updates)
+        .map(|stratifiers| Ok(criteria_event(&stratifiers)));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn criteria_event(stratifiers: &PublishedStratifiers) -> Event {
+    Event::default()
+        .event("criteria")
+        .json_data(stratifiers)
+        .expect("Failed to serialize JSON")
+}
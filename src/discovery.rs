@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    beam::broker_id, cache::Site, config::CONFIG, project::Project, queue_outdated_sites,
+    SharedState,
+};
+
+pub type DiscoveredSites = Arc<RwLock<BTreeSet<Site>>>; // replaced whenever the sites are discovered again
+
+/// Sites configured for a project, followed by the discovered ones that aren't configured if the project uses discovery
+pub fn with_discovered(project: &Project, discovered: &DiscoveredSites) -> Vec<Site> {
+    let mut sites = project.sites.clone();
+    if !project.discovery {
+        return sites;
+    }
+    for site in discovered.read().unwrap().iter() {
+        if !sites.contains(site) {
+            sites.push(site.clone());
+        }
+    }
+    sites
+}
+
+/// Site names of the proxies connected to our broker, the way tasks are addressed to {target_app}.{site}.{broker}
+fn sites_of_proxies(
+    proxies: Vec<String>,
+    broker_id: &str,
+    allow: &[Site],
+    deny: &[Site],
+) -> BTreeSet<Site> {
+    proxies
+        .iter()
+        .filter_map(|proxy| proxy.strip_suffix(broker_id)?.strip_suffix('.'))
+        .filter(|site| !site.is_empty() && !site.contains('.'))
+        .filter(|site| allow.is_empty() || allow.iter().any(|allowed| allowed == site))
+        .filter(|site| !deny.iter().any(|denied| denied == site))
+        .map(String::from)
+        .collect()
+}
+
+/// Asks Beam for the connected proxies once, and queues the sites that were newly discovered. The previously discovered sites are kept if it fails
+pub async fn discover_sites(shared_state: &SharedState) {
+    let proxies = match shared_state.beam.list_proxies().await {
+        Ok(proxies) => proxies,
+        Err(e) => {
+            warn!("Failed to discover sites, keeping the previously discovered ones: {e}");
+            return;
+        }
+    };
+    let sites = sites_of_proxies(
+        proxies,
+        &broker_id(),
+        &CONFIG.discovery_allow,
+        &CONFIG.discovery_deny,
+    );
+    {
+        let mut discovered = shared_state.discovered_sites.write().unwrap();
+        if *discovered == sites {
+            return;
+        }
+        let added: Vec<&Site> = sites.difference(&discovered).collect();
+        let removed: Vec<&Site> = discovered.difference(&sites).collect();
+        info!("Discovered sites changed, added {added:?}, removed {removed:?}");
+        *discovered = sites;
+    }
+    for project_state in shared_state.projects.values() {
+        for query_state in project_state.queries.values() {
+            queue_outdated_sites(query_state).await; // queries the sites added
+        }
+    }
+}
+
+/// Discovers the sites again periodically, if a discovery URL is configured
+pub fn spawn_site_discovery(shared_state: SharedState) {
+    if CONFIG.discovery_url.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CONFIG.discovery_interval).await;
+            discover_sites(&shared_state).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sites_of_proxies() {
+        let proxies = vec![
+            "proxy1.broker.samply.de".to_string(),
+            "proxy2.broker.samply.de".to_string(),
+            "proxy3.broker.samply.de".to_string(),
+            "proxy4.other-broker.samply.de".to_string(),
+            "sub.proxy5.broker.samply.de".to_string(),
+        ];
+        assert_eq!(
+            BTreeSet::from(["proxy1".to_string(), "proxy3".to_string()]),
+            sites_of_proxies(
+                proxies.clone(),
+                "broker.samply.de",
+                &[],
+                &["proxy2".to_string()]
+            )
+        );
+        assert_eq!(
+            BTreeSet::from(["proxy2".to_string()]),
+            sites_of_proxies(
+                proxies,
+                "broker.samply.de",
+                &["proxy2".to_string(), "proxy4".to_string()],
+                &[]
+            )
+        );
+    }
+
+    fn project(discovery: bool) -> Project {
+        let definition = serde_json::from_value(serde_json::json!({
+            "sites": ["proxy2", "proxy1"],
+            "body_file": "resources/body_bbmri.json",
+            "discovery": discovery,
+        }))
+        .unwrap();
+        let task_params = &CONFIG.projects["bbmri"].task_params;
+        Project::load("bbmri".into(), definition, "focus", task_params).unwrap()
+    }
+
+    #[test]
+    fn test_with_discovered() {
+        let discovered: DiscoveredSites = Arc::new(RwLock::new(BTreeSet::from([
+            "proxy1".to_string(),
+            "proxy3".to_string(),
+        ])));
+        assert_eq!(
+            vec!["proxy2", "proxy1", "proxy3"],
+            with_discovered(&project(true), &discovered)
+        );
+        assert_eq!(
+            vec!["proxy2", "proxy1"],
+            with_discovered(&project(false), &discovered)
+        );
+    }
+}
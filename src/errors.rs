@@ -6,10 +6,16 @@ pub enum PrismError {
     ParsingError(String),
     #[error("Beam error: {0}")]
     BeamError(String),
+    #[error("Serialization error: {0}")]
+    SerializationError(serde_json::Error),
     #[error("Deserialization error: {0}")]
     DeserializationError(serde_json::Error),
     #[error("Decode error: {0}")]
     DecodeError(base64::DecodeError),
-    #[error("Unexpected WorkStatus: {0:?}")]
-    UnexpectedWorkStatus(beam_lib::WorkStatus),
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+    #[error("Cache snapshot error: {0}")]
+    SnapshotError(String),
+    #[error("Invalid query body: {0}")]
+    InvalidBody(String),
 }
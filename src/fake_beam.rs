@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use beam_lib::{AppId, BeamError, MsgId, RawString, TaskRequest, WorkStatus};
+use futures_util::{future::BoxFuture, stream, StreamExt as _};
+use serde_json::json;
+
+use crate::{
+    cache::Site,
+    errors::PrismError,
+    transport::{BeamTransport, ResultEvent, ResultStream},
+};
+
+/// What a fake site answers, to every task sent to it
+#[derive(Debug, Clone)]
+pub enum Answer {
+    Result(WorkStatus, String), // body as sent by Focus, base64 encoded for succeeded results
+    Malformed(String),          // a message that isn't a result at all
+}
+
+impl Answer {
+    pub fn measure_report(measure_report: &str) -> Self {
+        Answer::Result(WorkStatus::Succeeded, BASE64.encode(measure_report))
+    }
+}
+
+/// Beam in memory, with sites answering as scripted
+#[derive(Default)]
+pub struct FakeBeam {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    answers: HashMap<Site, Vec<Answer>>, // sites without answers never answer
+    invalid: HashSet<Site>,
+    tasks: Vec<TaskRequest<RawString>>, // those accepted
+    broken_streams: usize,              // result streams that break off after the first result
+    stripped_streams: bool, // result streams fail right away, as if a proxy in between stripped them
+    closed_streams: usize, // result streams that end after the first result, as if closed by a proxy in between
+    streams: Vec<usize>,   // wait_count of each result stream opened
+    unreachable: bool,
+    proxies: Vec<String>, // connected to the broker
+    polls: usize,
+}
+
+impl FakeBeam {
+    pub fn answer(&self, site: &str, answers: Vec<Answer>) {
+        self.state
+            .lock()
+            .unwrap()
+            .answers
+            .insert(site.into(), answers);
+    }
+
+    /// Beam rejects tasks to the site as it doesn't know it
+    pub fn reject(&self, site: &str) {
+        self.state.lock().unwrap().invalid.insert(site.into());
+    }
+
+    /// The next result streams break off after the first result
+    pub fn break_streams(&self, count: usize) {
+        self.state.lock().unwrap().broken_streams = count;
+    }
+
+    /// Result streams fail before the first result, Prism should poll instead
+    pub fn strip_streams(&self) {
+        self.state.lock().unwrap().stripped_streams = true;
+    }
+
+    /// The next result streams end after the first result, without an error
+    pub fn close_streams(&self, count: usize) {
+        self.state.lock().unwrap().closed_streams = count;
+    }
+
+    pub fn set_reachable(&self, reachable: bool) {
+        self.state.lock().unwrap().unreachable = !reachable;
+    }
+
+    pub fn connect(&self, proxies: &[&str]) {
+        self.state.lock().unwrap().proxies =
+            proxies.iter().map(|proxy| proxy.to_string()).collect();
+    }
+
+    pub fn streams(&self) -> Vec<usize> {
+        self.state.lock().unwrap().streams.clone()
+    }
+
+    pub fn polls(&self) -> usize {
+        self.state.lock().unwrap().polls
+    }
+
+    pub fn tasks(&self) -> Vec<TaskRequest<RawString>> {
+        self.state.lock().unwrap().tasks.clone()
+    }
+
+    /// Results of all the sites of a task, and how many sites answer at all
+    fn results(&self, task_id: MsgId) -> Result<(Vec<ResultEvent>, usize), PrismError> {
+        let state = self.state.lock().unwrap();
+        let task = state
+            .tasks
+            .iter()
+            .find(|task| task.id == task_id)
+            .ok_or_else(|| PrismError::BeamError(format!("Task {task_id} not found")))?;
+        let mut events = Vec::new();
+        let mut answering = 0;
+        for app_id in &task.to {
+            let answers = state.answers.get(site(app_id)).cloned().unwrap_or_default();
+            if !answers.is_empty() {
+                answering += 1;
+            }
+            events.extend(answers.into_iter().map(|answer| {
+                match answer {
+                    Answer::Result(status, body) => ResultEvent::Result(
+                        serde_json::from_value(json!({
+                            "from": app_id,
+                            "to": [task.from],
+                            "task": task.id,
+                            "status": status,
+                            "body": body,
+                            "metadata": null,
+                        }))
+                        .expect("Failed to build a result"),
+                    ),
+                    Answer::Malformed(message) => ResultEvent::Malformed(message),
+                }
+            }));
+        }
+        Ok((events, answering))
+    }
+}
+
+fn site(app_id: &AppId) -> &str {
+    app_id.as_ref().split('.').nth(1).unwrap()
+}
+
+impl BeamTransport for FakeBeam {
+    fn post_task<'a>(
+        &'a self,
+        task: &'a TaskRequest<RawString>,
+    ) -> BoxFuture<'a, beam_lib::Result<()>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().unwrap();
+            let invalid: Vec<_> = task
+                .to
+                .iter()
+                .filter(|app_id| state.invalid.contains(site(app_id)))
+                .map(|app_id| app_id.proxy_id())
+                .collect();
+            if !invalid.is_empty() {
+                return Err(BeamError::InvalidReceivers(invalid));
+            }
+            state.tasks.push(task.clone());
+            Ok(())
+        })
+    }
+
+    fn stream_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+    ) -> BoxFuture<'_, Result<ResultStream, PrismError>> {
+        Box::pin(async move {
+            let (events, answering) = self.results(task_id)?;
+            let (broken, closed) = {
+                let mut state = self.state.lock().unwrap();
+                state.streams.push(wait_count);
+                if state.stripped_streams {
+                    return Err(PrismError::BeamError(
+                        "No event stream in the answer, a proxy in between may strip it".into(),
+                    ));
+                }
+                let broken = state.broken_streams > 0;
+                state.broken_streams = state.broken_streams.saturating_sub(1);
+                let closed = !broken && state.closed_streams > 0;
+                if closed {
+                    state.closed_streams -= 1;
+                }
+                (broken, closed)
+            };
+            let results = stream::iter(events.into_iter().map(Ok));
+            if broken {
+                let reset = PrismError::BeamError("Connection reset".into());
+                return Ok(results
+                    .take(1)
+                    .chain(stream::once(async { Err(reset) }))
+                    .boxed());
+            }
+            if closed {
+                return Ok(results.take(1).boxed());
+            }
+            if answering < wait_count {
+                // like Beam, counting the results sent before a reconnect too, and waiting for the sites that don't answer until the connection is closed
+                return Ok(results.chain(stream::pending()).boxed());
+            }
+            Ok(results.boxed())
+        })
+    }
+
+    fn poll_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+        wait_time: Duration,
+    ) -> BoxFuture<'_, Result<Vec<ResultEvent>, PrismError>> {
+        Box::pin(async move {
+            self.state.lock().unwrap().polls += 1;
+            let (events, answering) = self.results(task_id)?;
+            if answering < wait_count {
+                tokio::time::sleep(wait_time).await;
+            }
+            Ok(events)
+        })
+    }
+
+    fn check_proxy(&self) -> BoxFuture<'_, beam_lib::Result<()>> {
+        Box::pin(async move {
+            if self.state.lock().unwrap().unreachable {
+                return Err(BeamError::Other("Connection refused".into()));
+            }
+            Ok(())
+        })
+    }
+
+    fn list_proxies(&self) -> BoxFuture<'_, Result<Vec<String>, PrismError>> {
+        Box::pin(async move {
+            let state = self.state.lock().unwrap();
+            if state.unreachable {
+                return Err(PrismError::BeamError("Connection refused".into()));
+            }
+            Ok(state.proxies.clone())
+        })
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    criteria::{Criteria, Stratifiers},
+    errors::PrismError,
+};
+
+/// Parent codes of a hierarchical code system, for example ICD-10
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hierarchy {
+    /// Codes are rolled up into the part before this separator, for example C02.1 into C02
+    #[serde(default)]
+    truncate_at: Option<String>,
+    /// Ranges of codes after truncation, both ends included, for example "C00-C14": ["C00", "C14"]
+    #[serde(default)]
+    ranges: BTreeMap<String, (String, String)>,
+}
+
+pub type Hierarchies = BTreeMap<String, Hierarchy>; // by stratifier
+
+impl Hierarchy {
+    /// The truncated code a code is rolled up into, the code itself if it isn't truncated
+    fn category<'a>(&self, code: &'a str) -> &'a str {
+        match &self.truncate_at {
+            Some(separator) => code
+                .split_once(separator.as_str())
+                .map_or(code, |(category, _)| category),
+            None => code,
+        }
+    }
+
+    /// Parent codes among the rolled up criteria, each together with the children its count is added up from
+    pub fn families(&self, criteria: &Criteria) -> Vec<(String, Vec<String>)> {
+        let mut families: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for code in criteria.keys() {
+            let category = self.category(code);
+            if category != code && criteria.contains_key(category) {
+                families
+                    .entry(category.to_string())
+                    .or_default()
+                    .push(code.clone());
+            }
+        }
+        for (name, (from, to)) in &self.ranges {
+            if !criteria.contains_key(name) {
+                continue;
+            }
+            // a range is added up from its largest sub-ranges and the categories none of them covers, nested ranges have families of their own
+            let sub_ranges: Vec<(&String, &(String, String))> = self
+                .ranges
+                .iter()
+                .filter(|(_, bounds)| within(bounds, (from, to)))
+                .filter(|(_, bounds)| {
+                    !self.ranges.values().any(|other| {
+                        within(bounds, (&other.0, &other.1)) && within(other, (from, to))
+                    })
+                })
+                .collect();
+            let categories = criteria
+                .range(from.clone()..=to.clone())
+                .map(|(code, _)| code)
+                .filter(|code| self.category(code) == *code && !self.ranges.contains_key(*code))
+                .filter(|code| {
+                    !sub_ranges
+                        .iter()
+                        .any(|(_, (from, to))| (from..=to).contains(code))
+                });
+            let members = sub_ranges
+                .iter()
+                .map(|(sub_range, _)| *sub_range)
+                .filter(|sub_range| criteria.contains_key(*sub_range))
+                .chain(categories)
+                .cloned()
+                .collect();
+            families.insert(name.clone(), members);
+        }
+        families.into_iter().collect()
+    }
+}
+
+/// Whether a range lies within another one and isn't the same
+fn within(range: &(String, String), (from, to): (&String, &String)) -> bool {
+    from <= &range.0 && &range.1 <= to && (&range.0, &range.1) != (from, to)
+}
+
+pub fn load_hierarchies(path: &Path) -> Result<Hierarchies, PrismError> {
+    let hierarchies = fs::read_to_string(path).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "Hierarchy file {} can't be read: {e}",
+            path.display()
+        ))
+    })?;
+    serde_json::from_str(&hierarchies).map_err(|e| {
+        PrismError::ConfigError(format!("Hierarchy file {} is invalid: {e}", path.display()))
+    })
+}
+
+/// Adds counts for the parent codes to the stratifiers with a hierarchy, so that parent nodes in the search tree show expected counts.
+/// The counts of the children are added up, so patients with several child codes are counted more than once, just like when Lens adds them up.
+pub fn roll_up(stratifiers: Stratifiers, hierarchies: &Hierarchies) -> Stratifiers {
+    stratifiers
+        .into_iter()
+        .map(|(key, criteria)| match hierarchies.get(&key) {
+            Some(hierarchy) => {
+                let criteria = roll_up_criteria(criteria, hierarchy);
+                (key, criteria)
+            }
+            None => (key, criteria),
+        })
+        .collect()
+}
+
+fn roll_up_criteria(criteria: Criteria, hierarchy: &Hierarchy) -> Criteria {
+    let mut categories = Criteria::new(); // counts of the truncated codes, including the code itself if a site sends it
+    for (code, count) in &criteria {
+        let category = hierarchy.category(code);
+        *categories.entry(category.to_string()).or_insert(0) += count;
+    }
+
+    let mut rolled_up = criteria;
+    for (name, (from, to)) in &hierarchy.ranges {
+        let count: u64 = categories
+            .range(from.clone()..=to.clone())
+            .map(|(_, count)| count)
+            .sum();
+        if count > 0 {
+            rolled_up.insert(name.clone(), count);
+        }
+    }
+    rolled_up.extend(categories);
+    rolled_up
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HIERARCHY_ICD10: &str = include_str!("../resources/hierarchy_icd10.json");
+
+    #[test]
+    fn test_roll_up_icd10() {
+        let hierarchies: Hierarchies = serde_json::from_str(HIERARCHY_ICD10).unwrap();
+        let criteria: Criteria = [
+            ("C01".into(), 25),
+            ("C02.0".into(), 3),
+            ("C02.1".into(), 23),
+            ("C16".into(), 7),
+            ("C16.2".into(), 2),
+        ]
+        .into();
+        let stratifiers: Stratifiers = [
+            ("diagnosis".into(), criteria),
+            ("gender".into(), [("male".into(), 10)].into()),
+        ]
+        .into();
+
+        let rolled_up = roll_up(stratifiers, &hierarchies);
+
+        pretty_assertions::assert_eq!(
+            r#"{"diagnosis":{"C00-C14":51,"C00-C97":60,"C00-D48":60,"C01":25,"C02":26,"C02.0":3,"C02.1":23,"C15-C26":9,"C16":9,"C16.2":2},"gender":{"male":10}}"#,
+            serde_json::to_string(&rolled_up).unwrap()
+        );
+    }
+}
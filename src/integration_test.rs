@@ -0,0 +1,598 @@
+//! The whole pipeline from /criteria to a task, its results and the cache, against a fake Beam
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::BodyDataStream,
+    extract::{Json, Query, State},
+    http::StatusCode,
+    response::IntoResponse as _,
+};
+use beam_lib::WorkStatus;
+use futures_util::StreamExt as _;
+use serde_json::Value;
+
+use crate::{
+    config::CONFIG,
+    criteria_stream,
+    discovery::{discover_sites, DiscoveredSites},
+    fake_beam::{Answer, FakeBeam},
+    handle_get_criteria, metrics,
+    outcome::SiteState,
+    query_sites, queue_sites, spawn_querying_when_beam_ready, spawn_site_querying, status,
+    CriteriaParams, LensQuery, ProjectState, QueryState, SharedState,
+};
+
+const MEASURE_REPORT_BBMRI: &str = include_str!("../resources/test/measure_report_bbmri.json");
+
+fn shared_state(beam: &Arc<FakeBeam>) -> SharedState {
+    let discovered_sites = DiscoveredSites::default();
+    let projects = CONFIG
+        .projects
+        .iter()
+        .map(|(name, project)| {
+            let project_state = ProjectState::new(
+                project.clone(),
+                BTreeMap::new(),
+                beam.clone(),
+                discovered_sites.clone(),
+            );
+            (name.clone(), project_state)
+        })
+        .collect();
+    SharedState {
+        projects: Arc::new(projects),
+        cors_origins: Arc::new(RwLock::new(CONFIG.cors_origin.clone())),
+        beam: beam.clone(),
+        discovered_sites,
+        querying: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+fn query_state(shared_state: &SharedState) -> QueryState {
+    shared_state.projects["bbmri"].queries["default"].clone()
+}
+
+/// Criteria of all the sites with coverage, as Lens gets them
+async fn covered_criteria(shared_state: &SharedState) -> Value {
+    let params = CriteriaParams {
+        coverage: true,
+        ..Default::default()
+    };
+    let response = handle_get_criteria(
+        State(shared_state.clone()),
+        Query(params),
+        Json(LensQuery { sites: vec![] }),
+    )
+    .await
+    .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Criteria of the next event of a criteria stream
+async fn next_criteria(events: &mut BodyDataStream) -> Value {
+    let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+        .await
+        .expect("No event in time")
+        .unwrap()
+        .unwrap();
+    let event = String::from_utf8(event.to_vec()).unwrap();
+    let data = event
+        .strip_prefix("event: criteria\ndata: ")
+        .unwrap_or_else(|| panic!("Unexpected event {event}"));
+    serde_json::from_str(data.trim_end()).unwrap()
+}
+
+async fn wait_for_chunks(query_state: &QueryState) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let chunks = serde_json::to_value(&*query_state.chunks.lock().await).unwrap();
+            let finished = chunks
+                .as_array()
+                .unwrap()
+                .iter()
+                .all(|chunk| chunk["state"] == "done" || chunk["state"] == "failed");
+            if finished {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Chunks didn't finish in time");
+}
+
+async fn wait_for_chunk_state(query_state: &QueryState, state: &str) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let chunks = serde_json::to_value(&*query_state.chunks.lock().await).unwrap();
+            if chunks
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|chunk| chunk["state"] == state)
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("No chunk got to the state in time");
+}
+
+#[tokio::test]
+async fn test_criteria_pipeline() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer(
+        "proxy1",
+        vec![
+            Answer::Result(WorkStatus::Claimed, String::new()),
+            Answer::measure_report(MEASURE_REPORT_BBMRI),
+        ],
+    );
+    beam.answer(
+        "proxy2",
+        vec![
+            Answer::Result(WorkStatus::TempFailed, "Database unavailable".into()),
+            Answer::Result(WorkStatus::PermFailed, "Database unavailable".into()),
+        ],
+    );
+    beam.reject("proxy3");
+    beam.answer(
+        "proxy4",
+        vec![
+            Answer::Malformed("<html>Bad Gateway</html>".into()),
+            Answer::Result(WorkStatus::Succeeded, "not base64!".into()),
+        ],
+    );
+    // proxy5 never answers
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    let covered = covered_criteria(&shared_state).await;
+    assert_eq!(5, covered["missing_sites"].as_array().unwrap().len());
+    query_sites(query_state.clone()).await;
+    wait_for_chunk_state(&query_state, "collecting").await;
+    {
+        // the sites that were posted stay in flight until their task expires while their results are collected
+        let sites_in_flight = query_state.sites_in_flight.lock().await;
+        let mut in_flight: Vec<&str> = sites_in_flight
+            .iter()
+            .filter(|(_, in_flight)| in_flight.expires.is_some())
+            .map(|(site, _)| site.as_str())
+            .collect();
+        in_flight.sort();
+        assert_eq!(vec!["proxy1", "proxy2", "proxy4", "proxy5"], in_flight);
+    }
+    wait_for_chunks(&query_state).await;
+    assert!(query_state.sites_in_flight.lock().await.is_empty());
+
+    let tasks = beam.tasks();
+    assert_eq!(1, tasks.len());
+    let mut receivers: Vec<&str> = tasks[0].to.iter().map(|app_id| app_id.as_ref()).collect();
+    receivers.sort();
+    assert_eq!(
+        vec![
+            "focus.proxy1.broker",
+            "focus.proxy2.broker",
+            "focus.proxy4.broker",
+            "focus.proxy5.broker"
+        ],
+        receivers
+    );
+    assert!(query_state.quarantine.lock().await.contains("proxy3"));
+
+    let outcomes = query_state.site_outcomes.lock().await.clone();
+    assert_eq!(SiteState::Succeeded, outcomes["proxy1"].state);
+    assert_eq!(SiteState::Failing, outcomes["proxy2"].state);
+    assert_eq!(
+        Some("PermFailed: Database unavailable"),
+        outcomes["proxy2"].last_error.as_deref()
+    );
+    assert_eq!(SiteState::Failing, outcomes["proxy4"].state);
+    assert!(outcomes["proxy4"]
+        .last_error
+        .as_deref()
+        .unwrap()
+        .starts_with("undecodable result: "));
+    assert_eq!(SiteState::TimedOut, outcomes["proxy5"].state);
+
+    let covered = covered_criteria(&shared_state).await;
+    assert_eq!(
+        vec!["proxy1"],
+        covered["contributing_sites"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>()
+    );
+    assert!(!covered["criteria"].as_object().unwrap().is_empty());
+    // failing sites are backing off and proxy3 is quarantined, so none of the missing sites is queued again
+    assert!(query_state.sites_to_query.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_result_stream_breaking_off() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer("proxy1", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    beam.answer("proxy2", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    beam.break_streams(1);
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    queue_sites(&query_state, vec!["proxy1".into(), "proxy2".into()]).await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    // the stream is reconnected after it broke off, proxy1's result is collected only once
+    assert_eq!(2, beam.streams().len());
+    assert_eq!(0, beam.polls());
+    let chunks = query_state.chunks.lock().await;
+    let chunk = serde_json::to_value(&chunks[0]).unwrap();
+    assert_eq!("done", chunk["state"]);
+    let mut answered: Vec<&str> = chunk["answered"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|site| site.as_str().unwrap())
+        .collect();
+    answered.sort();
+    assert_eq!(vec!["proxy1", "proxy2"], answered);
+    assert_eq!(2, query_state.criteria_cache.lock().await.cache.len());
+}
+
+#[tokio::test]
+async fn test_polling_sites_that_only_claimed() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer(
+        "proxy1",
+        vec![Answer::Result(WorkStatus::Claimed, String::new())],
+    );
+    beam.answer(
+        "proxy2",
+        vec![Answer::Result(
+            WorkStatus::TempFailed,
+            "Database unavailable".into(),
+        )],
+    );
+    beam.strip_streams(); // falling back to polling
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    queue_sites(&query_state, vec!["proxy1".into(), "proxy2".into()]).await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    // every poll is answered right away, as both sites sent a result, but none of them is final
+    assert!(beam.polls() <= 3, "Polled {} times", beam.polls());
+    let outcomes = query_state.site_outcomes.lock().await.clone();
+    assert_eq!(SiteState::TimedOut, outcomes["proxy1"].state);
+    assert_eq!(SiteState::Failing, outcomes["proxy2"].state);
+}
+
+#[tokio::test]
+async fn test_startup_without_beam_proxy() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.set_reachable(false);
+    let shared_state = shared_state(&beam);
+    spawn_querying_when_beam_ready(shared_state.clone());
+
+    // the cache is served, but no site is queried until the proxy is up
+    let covered = covered_criteria(&shared_state).await;
+    assert_eq!(5, covered["missing_sites"].as_array().unwrap().len());
+    let (status, _) = status::handle_ready(State(shared_state.clone())).await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+    assert!(beam.tasks().is_empty());
+
+    beam.set_reachable(true);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while status::handle_ready(State(shared_state.clone())).await.0 != StatusCode::OK {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Prism didn't get ready in time");
+    let query_state = query_state(&shared_state);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while beam.tasks().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("No task was posted");
+    assert!(query_state.sites_to_query.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_site_discovery() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.connect(&[
+        "proxy0.broker", // Prism's own, denied
+        "proxy1.broker",
+        "proxy6.broker",
+        "proxy7.other-broker",
+    ]);
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    discover_sites(&shared_state).await;
+    assert!(query_state.sites_to_query.lock().await.contains("proxy6"));
+    let covered = covered_criteria(&shared_state).await;
+    assert_eq!(
+        serde_json::json!(["proxy1", "proxy2", "proxy3", "proxy4", "proxy5", "proxy6"]),
+        covered["missing_sites"]
+    );
+
+    // the discovered sites are kept if the broker can't be reached
+    beam.set_reachable(false);
+    discover_sites(&shared_state).await;
+    assert!(query_state.sites().contains(&"proxy6".to_string()));
+}
+
+#[tokio::test]
+async fn test_readiness_and_site_statuses() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer("proxy1", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    beam.answer(
+        "proxy2",
+        vec![Answer::Result(
+            WorkStatus::PermFailed,
+            "Database unavailable".into(),
+        )],
+    );
+    beam.reject("proxy3");
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    // not ready before the sites are queried, though the proxy is reachable
+    let (status, Json(readiness)) = status::handle_ready(State(shared_state.clone())).await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+    let readiness = serde_json::to_value(readiness).unwrap();
+    assert_eq!("reachable", readiness["beam_proxy"]["status"]);
+    assert_eq!(false, readiness["querying"]);
+
+    shared_state.querying.store(true, Ordering::Relaxed);
+    queue_sites(
+        &query_state,
+        vec!["proxy1".into(), "proxy2".into(), "proxy3".into()],
+    )
+    .await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    let (status, Json(readiness)) = status::handle_ready(State(shared_state.clone())).await;
+    assert_eq!(StatusCode::OK, status);
+    let readiness = serde_json::to_value(readiness).unwrap();
+    assert_eq!(5, readiness["projects"]["bbmri"]["sites_configured"]);
+    assert_eq!(1, readiness["projects"]["bbmri"]["sites_cached"]);
+
+    let Json(sites) = status::handle_sites(State(shared_state.clone())).await;
+    let sites = serde_json::to_value(sites).unwrap();
+    let sites = &sites["bbmri"]["default"];
+    assert_eq!(
+        vec!["proxy1", "proxy2", "proxy3", "proxy4", "proxy5"],
+        sites.as_object().unwrap().keys().collect::<Vec<_>>()
+    );
+    assert_eq!("succeeded", sites["proxy1"]["state"]);
+    assert_eq!(false, sites["proxy1"]["expired"]);
+    assert!(sites["proxy1"]["last_updated"].is_string());
+    assert_eq!("failing", sites["proxy2"]["state"]);
+    assert_eq!(1, sites["proxy2"]["consecutive_failures"]);
+    assert_eq!(
+        "PermFailed: Database unavailable",
+        sites["proxy2"]["last_error"]
+    );
+    assert!(sites["proxy2"]["retry_at"].is_string());
+    assert_eq!(true, sites["proxy3"]["quarantined"]);
+    assert_eq!(true, sites["proxy4"]["expired"]);
+    assert!(sites["proxy4"]["last_updated"].is_null());
+
+    // not ready anymore when the proxy goes down
+    beam.set_reachable(false);
+    let (status, Json(readiness)) = status::handle_ready(State(shared_state.clone())).await;
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+    let readiness = serde_json::to_value(readiness).unwrap();
+    assert_eq!("unreachable", readiness["beam_proxy"]["status"]);
+}
+
+#[tokio::test]
+async fn test_metrics_after_result() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer("proxy1", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    queue_sites(&query_state, vec!["proxy1".into()]).await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    let response = metrics::handle_metrics(State(shared_state.clone())).await;
+    assert_eq!(StatusCode::OK, response.status());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    // the counters are global and other tests add to them too, only the lines are checked
+    for line in [
+        "prism_beam_tasks_posted_total ",
+        "prism_beam_results_total{status=\"Succeeded\"} ",
+        "prism_results_cached_total ",
+        "prism_site_cache_age_seconds{project=\"bbmri\",query=\"default\",site=\"proxy1\"} ",
+        "prism_sites_to_query{project=\"bbmri\",query=\"default\"} 0",
+    ] {
+        assert!(
+            metrics.lines().any(|metric| metric.starts_with(line)),
+            "{line} missing in {metrics}"
+        );
+    }
+}
+
+// sleeping on the paused clock jumps ahead only once all the tasks are idle, so the debounce is measured exactly
+#[tokio::test(start_paused = true)]
+async fn test_requests_within_debounce() {
+    let beam = Arc::new(FakeBeam::default());
+    for site in ["proxy1", "proxy2", "proxy3", "proxy4", "proxy5"] {
+        beam.answer(site, vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    }
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+    spawn_site_querying(query_state.clone());
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while query_state.criteria_cache.lock().await.cache.len() < 5 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("The sites weren't cached in time");
+    assert_eq!(1, beam.tasks().len());
+
+    {
+        let mut criteria_cache = query_state.criteria_cache.lock().await;
+        criteria_cache.cache.remove("proxy1");
+        criteria_cache.cache.remove("proxy2");
+    }
+    // Lens asks for both sites a moment apart, within the debounce of a second
+    for site in ["proxy1", "proxy2"] {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handle_get_criteria(
+            State(shared_state.clone()),
+            Query(CriteriaParams::default()),
+            Json(LensQuery {
+                sites: vec![site.into()],
+            }),
+        )
+        .await
+        .unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while beam.tasks().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("No task was posted");
+    tokio::time::sleep(Duration::from_millis(1500)).await; // a second task would be posted after another debounce
+
+    let tasks = beam.tasks();
+    assert_eq!(2, tasks.len());
+    let mut receivers: Vec<&str> = tasks[1].to.iter().map(|app_id| app_id.as_ref()).collect();
+    receivers.sort();
+    assert_eq!(
+        vec!["focus.proxy1.broker", "focus.proxy2.broker"],
+        receivers
+    );
+}
+
+#[tokio::test]
+async fn test_criteria_stream() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer("proxy1", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    let query = serde_json::from_value(serde_json::json!({"sites": "proxy1"})).unwrap();
+    let sse = criteria_stream::handle_criteria_stream(State(shared_state.clone()), Query(query))
+        .await
+        .unwrap();
+    let mut events = sse.into_response().into_body().into_data_stream();
+
+    // nothing is cached yet, but proxy1 was queued by the request
+    assert_eq!(serde_json::json!({}), next_criteria(&mut events).await);
+    assert!(query_state.sites_to_query.lock().await.contains("proxy1"));
+
+    query_sites(query_state.clone()).await;
+    let criteria = next_criteria(&mut events).await;
+    assert!(!criteria.as_object().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_result_stream_reconnecting() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.answer("proxy1", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    beam.answer("proxy2", vec![Answer::measure_report(MEASURE_REPORT_BBMRI)]);
+    // proxy3 never answers
+    beam.close_streams(1);
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+    let mut cache_updates = query_state.cache_updates.subscribe();
+
+    queue_sites(
+        &query_state,
+        vec!["proxy1".into(), "proxy2".into(), "proxy3".into()],
+    )
+    .await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    // the stream ended after proxy1's result. Beam counts proxy1's result again, so the new stream waits for all the sites, which keeps it open for proxy3 until the deadline, and proxy1's result in it is skipped
+    assert_eq!(vec![3, 3], beam.streams());
+    assert_eq!(0, beam.polls());
+    let mut cached = Vec::new();
+    while let Ok(site) = cache_updates.try_recv() {
+        cached.push(site);
+    }
+    cached.sort();
+    assert_eq!(vec!["proxy1", "proxy2"], cached);
+    assert_eq!(
+        SiteState::Succeeded,
+        query_state.site_outcomes.lock().await["proxy2"].state
+    );
+}
+
+#[tokio::test]
+async fn test_chunk_of_rejected_sites() {
+    let beam = Arc::new(FakeBeam::default());
+    beam.reject("proxy3");
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    // Lens asking for a single unknown site
+    queue_sites(&query_state, vec!["proxy3".into()]).await;
+    query_sites(query_state.clone()).await;
+    wait_for_chunks(&query_state).await;
+
+    // no task without receivers is posted, and the site isn't queried again
+    assert!(beam.tasks().is_empty());
+    assert!(query_state.quarantine.lock().await.contains("proxy3"));
+    let chunks = serde_json::to_value(&*query_state.chunks.lock().await).unwrap();
+    assert_eq!("failed", chunks[0]["state"]);
+    assert!(query_state.sites_in_flight.lock().await.is_empty());
+    assert!(query_state.sites_to_query.lock().await.is_empty());
+    queue_sites(&query_state, vec!["proxy3".into()]).await;
+    assert!(query_state.sites_to_query.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_chunks_waiting_for_dead_sites() {
+    let beam = Arc::new(FakeBeam::default());
+    let shared_state = shared_state(&beam);
+    let query_state = query_state(&shared_state);
+
+    // more chunks than slots, none of the sites ever answers
+    for site in ["proxy1", "proxy2", "proxy3", "proxy4", "proxy5"] {
+        queue_sites(&query_state, vec![site.into()]).await;
+        query_sites(query_state.clone()).await;
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while beam.tasks().len() < 5 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Not all the chunks were posted");
+
+    // the last chunk didn't wait for a slot until the first one's deadline passed
+    let chunks = serde_json::to_value(&*query_state.chunks.lock().await).unwrap();
+    assert_eq!("collecting", chunks[0]["state"]);
+}
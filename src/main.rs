@@ -1,68 +1,387 @@
 mod beam;
+mod body;
+mod cache;
+mod chunk;
 mod config;
 mod criteria;
+mod criteria_stream;
+mod discovery;
 mod errors;
+#[cfg(test)]
+mod fake_beam;
+mod hierarchy;
+#[cfg(test)]
+mod integration_test;
 mod logger;
+mod mapping;
 mod measure_report;
+mod metrics;
+mod obfuscation;
+mod outcome;
+mod project;
+mod quarantine;
+mod reload;
+mod status;
+mod suppression;
+#[cfg(test)]
+mod test_config;
+mod tools;
+mod transport;
 
 use crate::errors::PrismError;
 use crate::{config::CONFIG, measure_report::extract_criteria, measure_report::MeasureReport};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use futures_util::{StreamExt as _, TryStreamExt};
-use std::collections::HashSet;
-use std::io;
+use futures_util::StreamExt as _;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::process::exit;
-use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::{net::TcpListener, sync::Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, Mutex, Notify, Semaphore},
+};
 
 use axum::{
-    extract::{Json, State},
+    extract::{Json, Path, Query, State},
     http::StatusCode,
+    middleware,
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{delete, get, post},
     Router,
 };
-use reqwest::{header, header::HeaderValue, Method};
+use reqwest::{header, Method};
 
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use beam::create_beam_task;
-use beam_lib::{AppId, BeamClient, MsgId};
+use beam::{create_beam_task, ResultMode, TaskParams};
+use beam_lib::{AppId, MsgId};
+use cache::{load_snapshot, save_snapshot, CacheSnapshot, Created, CriteriaCache, Site};
+use chrono::{DateTime, Utc};
+use chunk::{next_chunk_id, record_chunk, set_chunk_state, ChunkState, Chunks, InFlight};
+use config::CorsOrigins;
 use criteria::{combine_criteria_groups, Stratifiers};
-use std::{collections::HashMap, time::Duration};
-use tower_http::cors::CorsLayer;
+use discovery::{discover_sites, spawn_site_discovery, with_discovered, DiscoveredSites};
+use hierarchy::{roll_up, Hierarchies};
+use mapping::normalize;
+use obfuscation::{generation, obfuscate};
+use outcome::{Failure, SiteOutcome};
+use project::{NamedQuery, Project};
+use quarantine::Quarantine;
+use std::time::{Duration, Instant};
+use suppression::{suppress, PublishedStratifiers};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{debug, error, info, warn};
+use transport::{BeamProxy, BeamTransport, ResultEvent};
 
 use beam_lib::{RawString, TaskResult};
 
-static BEAM_CLIENT: Lazy<BeamClient> = Lazy::new(|| {
-    BeamClient::new(
-        &CONFIG.beam_app_id_long,
-        &CONFIG.api_key,
-        CONFIG.beam_proxy_url.clone(),
-    )
-});
+// tasks being posted at the same time, shared by all the projects and queries. Results are collected without a slot, so that chunks with dead sites don't hold up the others until the deadline
+static TASK_SLOTS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(CONFIG.task_concurrency));
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct LensQuery {
     sites: Vec<String>,
 }
 
-type Site = String;
-type Created = std::time::SystemTime; //epoch
+#[derive(Deserialize, Clone, Debug, Default)]
+struct CriteriaParams {
+    #[serde(default)]
+    query: Option<String>, // criteria of only this query of the project, all the queries merged if not given
+    #[serde(default)]
+    coverage: bool, // wrap the criteria in a CoveredCriteria envelope
+    #[serde(default)]
+    breakdown: bool, // criteria of each site separately instead of added up
+}
+
+/// Cached criteria of the requested sites, together with the information which sites are missing or stale
+#[derive(Debug, Default)]
+struct CollectedCriteria {
+    site_criteria: BTreeMap<Site, (Stratifiers, Created)>, // including the stale ones
+    missing_sites: Vec<Site>,
+    stale_sites: Vec<Site>,
+}
+
+impl CollectedCriteria {
+    /// Criteria added up for all the sites
+    fn combined(&self, hierarchies: &Hierarchies) -> PublishedStratifiers {
+        let generation = generation(
+            self.site_criteria
+                .iter()
+                .map(|(site, (_, created))| (site, created)),
+        );
+        publish(
+            self.site_criteria
+                .values()
+                .fold(Stratifiers::new(), |stratifiers, (criteria, _)| {
+                    combine_criteria_groups(stratifiers, criteria.clone())
+                }),
+            generation,
+            hierarchies,
+        )
+    }
+
+    /// Adds the criteria gathered for another query, a site is missing or stale if it is for any of the queries.
+    /// A stratifier both queries return is kept as it is, adding it up would count the same patients twice
+    fn merge(mut self, other: CollectedCriteria) -> Self {
+        for (site, (criteria, created)) in other.site_criteria {
+            let merged = match self.site_criteria.remove(&site) {
+                Some((mut merged, merged_created)) => {
+                    for (stratifier, counts) in criteria {
+                        merged.entry(stratifier).or_insert(counts);
+                    }
+                    (merged, merged_created.max(created)) // the generation changes whenever the criteria of one of the queries are cached again
+                }
+                None => (criteria, created),
+            };
+            self.site_criteria.insert(site, merged);
+        }
+        for site in other.missing_sites {
+            if !self.missing_sites.contains(&site) {
+                self.missing_sites.push(site);
+            }
+        }
+        for site in other.stale_sites {
+            if !self.stale_sites.contains(&site) {
+                self.stale_sites.push(site);
+            }
+        }
+        self
+    }
+
+    /// Criteria of each site separately
+    fn breakdown(&self, hierarchies: &Hierarchies) -> BTreeMap<Site, PublishedStratifiers> {
+        self.site_criteria
+            .iter()
+            .map(|(site, (criteria, created))| {
+                let generation = generation([(site, created)]);
+                let published = publish(criteria.clone(), generation, hierarchies);
+                (site.clone(), published)
+            })
+            .collect()
+    }
+}
+
+/// Prepares criteria to leave Prism, counts are obfuscated and small counts suppressed according to the config and the project's hierarchies
+fn publish(
+    stratifiers: Stratifiers,
+    generation: u64,
+    hierarchies: &Hierarchies,
+) -> PublishedStratifiers {
+    suppress(
+        obfuscate(stratifiers, &CONFIG.obfuscation, generation),
+        CONFIG.suppression_threshold,
+        CONFIG.suppression_mode,
+        hierarchies,
+    )
+}
+
+#[derive(Serialize, Debug)]
+struct CoveredCriteria<T> {
+    criteria: T,
+    contributing_sites: BTreeMap<Site, String>, // RFC 3339 time the criteria of the site were cached
+    missing_sites: Vec<Site>,
+    stale_sites: Vec<Site>,
+    generated_at: String,
+}
 
-#[derive(Debug, Clone)]
-struct CriteriaCache {
-    cache: HashMap<Site, (Stratifiers, Created)>,
+impl<T: Serialize> CoveredCriteria<T> {
+    fn new(collected: &CollectedCriteria, criteria: T) -> Self {
+        CoveredCriteria {
+            criteria,
+            contributing_sites: collected
+                .site_criteria
+                .iter()
+                .map(|(site, (_, created))| {
+                    (site.clone(), DateTime::<Utc>::from(*created).to_rfc3339())
+                })
+                .collect(),
+            missing_sites: collected.missing_sites.clone(),
+            stale_sites: collected.stale_sites.clone(),
+            generated_at: Utc::now().to_rfc3339(),
+        }
+    }
 }
-const CRITERIACACHE_TTL: Duration = Duration::from_secs(7200); //cached criteria expire after 2h
 
 #[derive(Clone)]
 struct SharedState {
+    projects: Arc<BTreeMap<String, ProjectState>>,
+    cors_origins: Arc<RwLock<CorsOrigins>>, // swapped when the configuration is reloaded
+    beam: Arc<dyn BeamTransport>,
+    discovered_sites: DiscoveredSites, // sites of the proxies connected to the broker, shared by the projects
+    querying: Arc<AtomicBool>, // set once the Beam proxy was reachable and the sites are queried
+}
+
+impl SharedState {
+    /// The project with the name, or the default project if no name is given
+    fn project(&self, name: Option<&str>) -> Result<&ProjectState, (StatusCode, String)> {
+        let name = name.or(CONFIG.default_project.as_deref()).ok_or((
+            StatusCode::NOT_FOUND,
+            "Several projects are configured, use /criteria/{project}".to_string(),
+        ))?;
+        self.projects.get(name).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Project {name} is not configured"),
+        ))
+    }
+}
+
+type SharedProject = Arc<RwLock<Arc<Project>>>; // swapped when the configuration is reloaded
+
+#[derive(Clone)]
+struct ProjectState {
+    project: SharedProject,
+    queries: BTreeMap<String, QueryState>,
+    cache_updates: broadcast::Sender<Site>, //announces sites whose criteria were just cached, for any of the queries
+    quarantine: Arc<Mutex<Quarantine>>, //sites rejected as invalid receivers, shared by the queries
+    discovered_sites: DiscoveredSites,
+}
+
+/// Whether restored criteria of a query were computed with its current body, mapping and hierarchies
+fn is_current(project: &Project, query: &str, criteria_cache: &CriteriaCache) -> bool {
+    if !project.queries.contains_key(query) {
+        return false;
+    }
+    let current = criteria_cache.fingerprint.as_deref() == Some(&project.fingerprint(query));
+    if !current {
+        info!(
+            "Query {query} of project {} or the processing of its results changed since the cache was saved, not restoring its cached criteria",
+            project.name
+        );
+    }
+    current
+}
+
+impl ProjectState {
+    fn new(
+        project: Arc<Project>,
+        mut criteria_caches: BTreeMap<String, CriteriaCache>,
+        beam: Arc<dyn BeamTransport>,
+        discovered_sites: DiscoveredSites,
+    ) -> Self {
+        let cache_updates = broadcast::channel(64).0;
+        let query_names: Vec<String> = project.queries.keys().cloned().collect();
+        criteria_caches.retain(|query, criteria_cache| is_current(&project, query, criteria_cache));
+        let project = Arc::new(RwLock::new(project));
+        let quarantine = Arc::new(Mutex::new(Quarantine::default()));
+        let queries = query_names
+            .into_iter()
+            .map(|name| {
+                let criteria_cache = criteria_caches.remove(&name).unwrap_or_default();
+                let query_state = QueryState::new(
+                    project.clone(),
+                    name.clone(),
+                    criteria_cache,
+                    cache_updates.clone(),
+                    quarantine.clone(),
+                    beam.clone(),
+                    discovered_sites.clone(),
+                );
+                (name, query_state)
+            })
+            .collect();
+        ProjectState {
+            project,
+            queries,
+            cache_updates,
+            quarantine,
+            discovered_sites,
+        }
+    }
+
+    /// The project as currently configured
+    fn project(&self) -> Arc<Project> {
+        self.project.read().unwrap().clone()
+    }
+
+    /// Sites of the project, those configured and those discovered
+    fn sites(&self) -> Vec<Site> {
+        with_discovered(&self.project(), &self.discovered_sites)
+    }
+
+    /// The query with the name, or all the queries of the project if no name is given
+    fn queries(&self, name: Option<&str>) -> Result<Vec<QueryState>, (StatusCode, String)> {
+        match name {
+            Some(name) => match self.queries.get(name) {
+                Some(query_state) => Ok(vec![query_state.clone()]),
+                None => Err((
+                    StatusCode::NOT_FOUND,
+                    format!(
+                        "Query {name} is not configured for project {}",
+                        self.project().name
+                    ),
+                )),
+            },
+            None => Ok(self.queries.values().cloned().collect()),
+        }
+    }
+}
+
+/// State of one of the queries of a project, each has its own cache and sends its own tasks
+#[derive(Clone)]
+struct QueryState {
+    project: SharedProject,
+    name: String,
     criteria_cache: Arc<Mutex<CriteriaCache>>,
     sites_to_query: Arc<Mutex<HashSet<String>>>,
+    sites_in_flight: Arc<Mutex<HashMap<Site, InFlight>>>,
+    chunks: Arc<Mutex<Chunks>>,
+    site_outcomes: Arc<Mutex<HashMap<Site, SiteOutcome>>>,
+    query_trigger: Arc<Notify>,
+    cache_updates: broadcast::Sender<Site>,
+    quarantine: Arc<Mutex<Quarantine>>,
+    beam: Arc<dyn BeamTransport>,
+    discovered_sites: DiscoveredSites,
+}
+
+impl QueryState {
+    fn new(
+        project: SharedProject,
+        name: String,
+        criteria_cache: CriteriaCache,
+        cache_updates: broadcast::Sender<Site>,
+        quarantine: Arc<Mutex<Quarantine>>,
+        beam: Arc<dyn BeamTransport>,
+        discovered_sites: DiscoveredSites,
+    ) -> Self {
+        let sites_to_query: HashSet<String> = HashSet::new();
+        //accumulates sites to query, those for which Lens asked for criteria, and they either weren't cached or the cache had expired, emptied when task to sites sent
+
+        let sites_in_flight: HashMap<Site, InFlight> = HashMap::new();
+        //sites in a chunk waiting to be posted or whose results are still awaited, with the time the task expires, so that they aren't queried again before
+
+        QueryState {
+            project,
+            name,
+            criteria_cache: Arc::new(Mutex::new(criteria_cache)),
+            sites_to_query: Arc::new(Mutex::new(sites_to_query)),
+            sites_in_flight: Arc::new(Mutex::new(sites_in_flight)),
+            chunks: Arc::new(Mutex::new(Chunks::new())), // most recent chunks of sites and what became of them
+            site_outcomes: Arc::new(Mutex::new(HashMap::new())), // what became of the latest queries to each site
+            query_trigger: Arc::new(Notify::new()), //notified when Lens asks for sites that need to be queried
+            cache_updates,
+            quarantine,
+            beam,
+            discovered_sites,
+        }
+    }
+
+    /// The project the query belongs to, shared with its project state so that a reload reaches every query
+    fn project(&self) -> Arc<Project> {
+        self.project.read().unwrap().clone()
+    }
+
+    /// Sites the query is sent to, the same for all the queries of the project
+    fn sites(&self) -> Vec<Site> {
+        with_discovered(&self.project(), &self.discovered_sites)
+    }
+
+    /// The query as currently configured, reloading never removes a query from its project
+    fn query(&self) -> Arc<NamedQuery> {
+        self.project().queries[&self.name].clone()
+    }
 }
 
 #[tokio::main]
@@ -81,53 +400,114 @@ pub async fn main() {
     Successfully retrieved results are cached.
     */
 
-    let criteria_cache: CriteriaCache = CriteriaCache {
-        //stores criteria for CRITERIACACHE_TTL to avoid querying the sites and processing results too often
-        cache: HashMap::new(),
-    };
-
-    let sites_to_query: HashSet<String> = HashSet::new();
-    //accumulates sites to query, those for which Lens asked for criteria, and they either weren't cached or the cache had expired, emptied when task to sites sent
-
-    let shared_state = SharedState {
-        criteria_cache: Arc::new(Mutex::new(criteria_cache)),
-        sites_to_query: Arc::new(Mutex::new(sites_to_query)),
-    };
+    tools::run_if_requested();
 
     if let Err(e) = logger::init_logger() {
         error!("Cannot initialize logger: {}", e);
         exit(1);
     };
 
-    if let Err(e) = wait_for_beam_proxy().await {
-        error!("Beam doesn't work, it doesn't make sense that I run: {}", e);
-        exit(2);
-    }
+    let mut snapshot: CacheSnapshot = match &CONFIG.cache_file {
+        //stores criteria for CRITERIACACHE_TTL to avoid querying the sites and processing results too often
+        Some(cache_file) => load_snapshot(cache_file), // restored with the original timestamps, so expired entries are still queried again
+        None => CacheSnapshot::new(),
+    };
 
-    info!("Beam ready");
+    let beam: Arc<dyn BeamTransport> = Arc::new(BeamProxy::new());
+    let discovered_sites = DiscoveredSites::default();
+    let shared_state = SharedState {
+        projects: Arc::new(
+            CONFIG
+                .projects
+                .iter()
+                .map(|(name, project)| {
+                    let criteria_caches = snapshot.remove(name).unwrap_or_default();
+                    (
+                        name.clone(),
+                        ProjectState::new(
+                            project.clone(),
+                            criteria_caches,
+                            beam.clone(),
+                            discovered_sites.clone(),
+                        ),
+                    )
+                })
+                .collect(),
+        ),
+        cors_origins: Arc::new(RwLock::new(CONFIG.cors_origin.clone())),
+        beam,
+        discovered_sites,
+        querying: Arc::new(AtomicBool::new(false)),
+    };
 
-    spawn_site_querying(shared_state.clone());
+    // the cached criteria are served right away, even if the Beam proxy isn't up yet
+    spawn_querying_when_beam_ready(shared_state.clone());
+    spawn_cache_saving(shared_state.clone());
+    reload::spawn_reloading(shared_state.clone());
 
+    let cors_origins = shared_state.cors_origins.clone();
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
-        .allow_origin(CONFIG.cors_origin.clone())
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            cors_origins.read().unwrap().allows(origin)
+        }))
         .allow_headers([header::CONTENT_TYPE]);
 
     let app = Router::new()
         .route("/criteria", post(handle_get_criteria)) //here Lens asks for criteria for sites in its configuration
-        .with_state(shared_state)
+        .route("/criteria/{project}", post(handle_get_project_criteria)) //same for one of several projects
+        .route(
+            "/criteria/stream",
+            get(criteria_stream::handle_criteria_stream),
+        ) //same, but the criteria are sent again whenever results for one of the sites arrive
+        .route(
+            "/criteria/{project}/stream",
+            get(criteria_stream::handle_project_criteria_stream),
+        )
+        .route("/health", get(status::handle_health))
+        .route("/ready", get(status::handle_ready))
+        .route_layer(middleware::from_fn(metrics::track_http))
+        .with_state(shared_state.clone())
         .layer(cors);
 
+    // operator endpoints, they tell what the sites answered and change what is queried
+    let admin = Router::new()
+        .route("/metrics", get(metrics::handle_metrics))
+        .route("/sites", get(status::handle_sites))
+        .route("/tasks", get(status::handle_tasks))
+        .route("/quarantine", get(quarantine::handle_quarantine))
+        .route(
+            "/quarantine/{project}/{site}",
+            delete(quarantine::handle_release),
+        )
+        .route_layer(middleware::from_fn(metrics::track_http))
+        .with_state(shared_state.clone());
+    let admin_listener = match TcpListener::bind(CONFIG.admin_bind_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Cannot serve the operator endpoints at --admin-bind-addr {}: {e}",
+                CONFIG.admin_bind_addr
+            );
+            exit(1);
+        }
+    };
+    tokio::spawn(async move {
+        axum::serve(admin_listener, admin.into_make_service())
+            .await
+            .unwrap()
+    });
+
     axum::serve(
         TcpListener::bind(CONFIG.bind_addr).await.unwrap(),
         app.into_make_service(),
     )
-    .with_graceful_shutdown(wait_for_shutdown())
+    .with_graceful_shutdown(wait_for_shutdown(shared_state))
     .await
     .unwrap()
 }
 
-async fn wait_for_shutdown() {
+async fn wait_for_shutdown(shared_state: SharedState) {
     #[cfg(unix)]
     {
         // Required for proper shutdown in Docker
@@ -136,47 +516,191 @@ async fn wait_for_shutdown() {
             signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
         sigterm.recv().await.expect("Failed to receive SIGTERM");
         info!("Received SIGTERM, shutting down...");
-        return;
+        save_cache(&shared_state).await;
     }
     // On other platforms we let the OS handle the shutdown
     #[cfg(not(unix))]
-    std::future::pending::<()>().await;
+    {
+        let _ = shared_state;
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn save_cache(shared_state: &SharedState) {
+    let Some(cache_file) = &CONFIG.cache_file else {
+        return;
+    };
+    let mut snapshot = CacheSnapshot::new();
+    for (name, project_state) in shared_state.projects.iter() {
+        let mut criteria_caches = BTreeMap::new();
+        for (query, query_state) in &project_state.queries {
+            let mut criteria_cache = query_state.criteria_cache.lock().await.clone();
+            criteria_cache.fingerprint = Some(project_state.project().fingerprint(query));
+            criteria_caches.insert(query.clone(), criteria_cache);
+        }
+        snapshot.insert(name.clone(), criteria_caches);
+    }
+    match save_snapshot(&snapshot, cache_file) {
+        Ok(()) => debug!("Saved cached criteria to {}", cache_file.display()),
+        Err(e) => warn!("Failed to save the criteria cache: {e}"),
+    }
 }
 
-fn spawn_site_querying(shared_state: SharedState) {
+fn spawn_cache_saving(shared_state: SharedState) {
+    if CONFIG.cache_file.is_none() {
+        return;
+    }
     tokio::spawn(async move {
         loop {
-            if let Err(e) = query_sites(shared_state.clone(), Some(&CONFIG.sites)).await {
-                warn!("Failed to query sites: {e}. Will try again in 5 seconds");
-            } else {
-                break;
+            tokio::time::sleep(CONFIG.cache_save_interval).await;
+            save_cache(&shared_state).await;
+        }
+    });
+}
+
+/// Waits for the Beam proxy in the background, then discovers the sites and starts querying them
+fn spawn_querying_when_beam_ready(shared_state: SharedState) {
+    tokio::spawn(async move {
+        wait_for_beam_proxy(&*shared_state.beam).await;
+        info!("Beam ready");
+
+        if CONFIG.discovery_url.is_some() {
+            discover_sites(&shared_state).await; // before the initial querying, so that the discovered sites are queried right away
+        }
+        for project_state in shared_state.projects.values() {
+            for query_state in project_state.queries.values() {
+                spawn_site_querying(query_state.clone());
             }
-            tokio::time::sleep(Duration::from_secs(5)).await;
         }
+        spawn_site_discovery(shared_state.clone());
+        shared_state.querying.store(true, Ordering::Relaxed);
+    });
+}
+
+fn spawn_site_querying(query_state: QueryState) {
+    tokio::spawn(async move {
+        queue_sites(&query_state, query_state.sites()).await;
+        query_sites(query_state.clone()).await;
         loop {
-            if let Err(e) = query_sites(shared_state.clone(), None).await {
-                warn!("Failed to query sites: {e}. Will try again later");
+            tokio::select! {
+                _ = query_state.query_trigger.notified() => {
+                    // Lens usually asks for the same sites several times in a row, waiting a moment gets them all into one task
+                    tokio::time::sleep(CONFIG.query_debounce).await;
+                }
+                _ = tokio::time::sleep(query_state.query().refresh_interval) => {
+                    // sites left over from a failed attempt are retried, and expired criteria refreshed, without waiting for Lens
+                    queue_outdated_sites(&query_state).await;
+                }
             }
-            tokio::time::sleep(Duration::from_secs(15 * 60)).await;
+            query_sites(query_state.clone()).await;
         }
     });
 }
 
 async fn handle_get_criteria(
     State(shared_state): State<SharedState>,
+    Query(params): Query<CriteriaParams>,
     Json(query): Json<LensQuery>,
 ) -> Result<Response, (StatusCode, String)> {
-    let mut stratifiers: Stratifiers = Stratifiers::new(); // this is going to be aggregated criteria for all the sites
+    let project_state = shared_state.project(None)?;
+    respond_with_criteria(project_state, params, query).await
+}
 
-    let mut sites = query.sites;
+async fn handle_get_project_criteria(
+    State(shared_state): State<SharedState>,
+    Path(project): Path<String>,
+    Query(params): Query<CriteriaParams>,
+    Json(query): Json<LensQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let project_state = shared_state.project(Some(&project))?;
+    respond_with_criteria(project_state, params, query).await
+}
 
-    // allowing empty list of sites in the request because Spot is going to query with the empty list and expect response for the sites in Prism's config
+async fn respond_with_criteria(
+    project_state: &ProjectState,
+    params: CriteriaParams,
+    query: LensQuery,
+) -> Result<Response, (StatusCode, String)> {
+    let query_states = project_state.queries(params.query.as_deref())?;
+    let collected =
+        collect_criteria(&query_states, requested_sites(project_state, query.sites)).await;
 
+    let hierarchies = &project_state.project().hierarchies;
+    let response_json = match (params.breakdown, params.coverage) {
+        (false, false) => serde_json::to_string(&collected.combined(hierarchies)),
+        (true, false) => serde_json::to_string(&collected.breakdown(hierarchies)),
+        (false, true) => serde_json::to_string(&CoveredCriteria::new(
+            &collected,
+            collected.combined(hierarchies),
+        )),
+        (true, true) => serde_json::to_string(&CoveredCriteria::new(
+            &collected,
+            collected.breakdown(hierarchies),
+        )),
+    }
+    .expect("Failed to serialize JSON");
+
+    let response_builder = Response::builder().status(StatusCode::OK);
+
+    Ok(response_builder
+        .body(axum::body::Body::from(response_json))
+        .unwrap()
+        .into_response())
+}
+
+fn requested_sites(project_state: &ProjectState, sites: Vec<Site>) -> Vec<Site> {
+    // allowing empty list of sites in the request because Spot is going to query with the empty list and expect response for the sites in Prism's config
     if sites.is_empty() {
-        sites = CONFIG.sites.clone();
+        project_state.sites()
+    } else {
+        sites
     }
+}
+
+/// Gathers cached criteria of the queries for the sites and queues the sites which are missing in the cache or expired
+async fn collect_criteria(query_states: &[QueryState], sites: Vec<Site>) -> CollectedCriteria {
+    let mut merged = CollectedCriteria::default();
+    for query_state in query_states {
+        let collected = gather_cached(
+            &*query_state.criteria_cache.lock().await,
+            query_state.query().ttl,
+            sites.clone(),
+        );
+
+        let sites_to_query = collected
+            .missing_sites
+            .iter()
+            .chain(&collected.stale_sites)
+            .cloned()
+            .collect();
+        queue_sites(query_state, sites_to_query).await;
+
+        merged = merged.merge(collected);
+    }
+    merged
+}
 
-    let criteria_cache = shared_state.criteria_cache.lock().await;
+/// Gathers cached criteria of the queries for the sites, without queuing the missing ones
+async fn gather_all_cached(query_states: &[QueryState], sites: &[Site]) -> CollectedCriteria {
+    let mut merged = CollectedCriteria::default();
+    for query_state in query_states {
+        let collected = gather_cached(
+            &*query_state.criteria_cache.lock().await,
+            query_state.query().ttl,
+            sites.to_vec(),
+        );
+        merged = merged.merge(collected);
+    }
+    merged
+}
+
+/// Gathers cached criteria of one query for the sites, without queuing the missing ones
+fn gather_cached(
+    criteria_cache: &CriteriaCache,
+    ttl: Duration,
+    sites: Vec<Site>,
+) -> CollectedCriteria {
+    let mut collected = CollectedCriteria::default();
 
     for site in sites {
         debug!("Request for site {}", &site);
@@ -185,197 +709,711 @@ async fn handle_get_criteria(
                 debug!("Results for site {} found in cache", &site);
 
                 // Include cached result in response even if expired, so the client gets something
-                stratifiers = combine_criteria_groups(stratifiers, cached.0.clone());
+                collected.site_criteria.insert(site.clone(), cached.clone());
 
-                if SystemTime::now().duration_since(cached.1).unwrap() >= CRITERIACACHE_TTL {
+                if CriteriaCache::is_expired(&cached.1, ttl) {
                     debug!(
                         "Results for site {} in cache sadly expired, will query again",
                         &site
                     );
-                    shared_state.sites_to_query.lock().await.insert(site);
+                    collected.stale_sites.push(site);
                 }
             }
             None => {
                 debug!("Results for site {} not found in cache, will query", &site);
-                shared_state.sites_to_query.lock().await.insert(site);
+                collected.missing_sites.push(site);
             }
         }
     }
 
-    let stratifiers_json = serde_json::to_string(&stratifiers).expect("Failed to serialize JSON");
+    collected
+}
 
-    let response_builder = Response::builder().status(StatusCode::OK);
+/// Queues the configured and discovered sites whose criteria are missing in the cache or expired
+async fn queue_outdated_sites(query_state: &QueryState) {
+    let collected = gather_cached(
+        &*query_state.criteria_cache.lock().await,
+        query_state.query().ttl,
+        query_state.sites(),
+    );
+    let sites_to_query = collected
+        .missing_sites
+        .into_iter()
+        .chain(collected.stale_sites)
+        .collect();
+    queue_sites(query_state, sites_to_query).await;
+}
 
-    Ok(response_builder
-        .body(axum::body::Body::from(stratifiers_json))
-        .unwrap()
-        .into_response())
+/// Adds sites to the set of sites to query and wakes up the querying process, unless they are in a chunk already awaiting results, failed recently or are quarantined
+async fn queue_sites(query_state: &QueryState, sites: Vec<Site>) {
+    let sites = without_quarantined(query_state, sites).await;
+    let sites: Vec<Site> = {
+        let site_outcomes = query_state.site_outcomes.lock().await;
+        sites
+            .into_iter()
+            .filter(|site| match site_outcomes.get(site) {
+                Some(outcome) if outcome.is_backing_off() => {
+                    debug!("Site {} failed recently, backing off", site);
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    };
+    let sites: Vec<Site> = {
+        let sites_in_flight = query_state.sites_in_flight.lock().await;
+        sites
+            .into_iter()
+            .filter(|site| match sites_in_flight.get(site) {
+                Some(in_flight) if in_flight.is_active() => {
+                    debug!("Site {} already in a chunk, waiting for results", site);
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    };
+    let mut sites_to_query = query_state.sites_to_query.lock().await;
+    let mut queued = false;
+    for site in sites {
+        queued |= sites_to_query.insert(site);
+    }
+    if queued {
+        query_state.query_trigger.notify_one();
+    }
 }
 
-async fn post_query(shared_state: SharedState, sites: Vec<String>) -> Result<(), PrismError> {
-    if sites.is_empty() {
-        info!("No sites to query");
-        return Ok(());
+async fn without_quarantined(query_state: &QueryState, sites: Vec<Site>) -> Vec<Site> {
+    let quarantine = query_state.quarantine.lock().await;
+    sites
+        .into_iter()
+        .filter(|site| {
+            let quarantined = quarantine.contains(site);
+            if quarantined {
+                debug!("Site {} is quarantined, not querying it", site);
+            }
+            !quarantined
+        })
+        .collect()
+}
+
+/// Splits the sites into chunks, one task is sent for each set of task parameters and chunk of sites
+async fn post_query(query_state: &QueryState, sites: Vec<Site>) {
+    let sites = without_quarantined(query_state, sites).await; // possibly quarantined by another query since they were queued
+    let project = query_state.project();
+    let mut groups: Vec<(TaskParams, Vec<Site>)> = Vec::new();
+    for site in sites {
+        let params = project.task_params(&site);
+        match groups
+            .iter_mut()
+            .find(|(group_params, _)| group_params == params)
+        {
+            Some((_, group_sites)) => group_sites.push(site),
+            None => groups.push((params.clone(), vec![site])),
+        }
     }
-    let wait_count = sites.len();
-    let site_display = sites.join(", ");
-    let mut task = create_beam_task(sites);
-    info!("Querying sites {:?}", site_display);
-
-    match BEAM_CLIENT.post_task(&task).await {
-        Ok(()) => (),
-        Err(beam_lib::BeamError::InvalidReceivers(invalid)) => {
-            task.to.retain(|t| !invalid.contains(&t.proxy_id()));
-            BEAM_CLIENT
-                .post_task(&task)
-                .await
-                .map_err(|e| PrismError::BeamError(format!("Unable to post a query: {}", e)))?;
+
+    for (params, sites) in groups {
+        let chunk_size = CONFIG.task_chunk_size.unwrap_or(sites.len());
+        for sites in sites.chunks(chunk_size) {
+            let chunk = next_chunk_id();
+            {
+                let mut sites_in_flight = query_state.sites_in_flight.lock().await;
+                for site in sites {
+                    sites_in_flight.insert(
+                        site.clone(),
+                        InFlight {
+                            chunk,
+                            expires: None,
+                        },
+                    );
+                }
+            }
+            record_chunk(&mut *query_state.chunks.lock().await, chunk, sites.to_vec());
+            tokio::spawn(run_chunk(
+                query_state.clone(),
+                params.clone(),
+                chunk,
+                sites.to_vec(),
+            ));
+        }
+    }
+}
+
+/// Posts the task to a chunk of sites once a slot is free, and collects its results independently of the other chunks
+async fn run_chunk(query_state: QueryState, params: TaskParams, chunk: u64, sites: Vec<Site>) {
+    let slot = TASK_SLOTS
+        .acquire()
+        .await
+        .expect("Task slots are never closed");
+    let query = query_state.query(); // as configured when the task is actually sent
+    let (rejected, posted) = post_task(&query_state, &query, &params, sites.clone()).await;
+    drop(slot);
+    if !rejected.is_empty() {
+        release_sites(&query_state, chunk, &rejected).await; // the others are still in flight, or queued again below
+        let mut quarantine = query_state.quarantine.lock().await;
+        for site in &rejected {
+            warn!(
+                "Site {} was rejected as an invalid receiver, quarantining it for {:?}",
+                site, CONFIG.quarantine_duration
+            );
+            quarantine.add(
+                site.clone(),
+                "Rejected by Beam as an invalid receiver".to_string(),
+                CONFIG.quarantine_duration,
+            );
+        }
+    }
+    let (task_id, posted) = match posted {
+        Ok(Some(posted)) => posted,
+        Ok(None) => {
+            let error = "All the sites were rejected as invalid receivers".to_string();
+            set_chunk_state(
+                &mut *query_state.chunks.lock().await,
+                chunk,
+                ChunkState::Failed { error },
+            );
+            return;
         }
         Err(e) => {
-            return Err(PrismError::BeamError(format!(
-                "Unable to post a query: {}",
-                e
-            )));
+            warn!("{e}. Sites of chunk {chunk} are queried again later");
+            set_chunk_state(
+                &mut *query_state.chunks.lock().await,
+                chunk,
+                ChunkState::Failed {
+                    error: e.to_string(),
+                },
+            );
+            let sites = sites
+                .into_iter()
+                .filter(|site| !rejected.contains(site))
+                .collect();
+            requeue_sites(&query_state, chunk, sites).await;
+            return;
+        }
+    };
+    {
+        let mut site_outcomes = query_state.site_outcomes.lock().await;
+        for site in &posted {
+            site_outcomes.entry(site.clone()).or_default().attempt();
+        }
+    }
+    let expires = Instant::now() + params.ttl;
+    {
+        let mut sites_in_flight = query_state.sites_in_flight.lock().await;
+        for site in &posted {
+            if let Some(in_flight) = sites_in_flight
+                .get_mut(site)
+                .filter(|in_flight| in_flight.chunk == chunk)
+            {
+                in_flight.expires = Some(expires);
+            }
         }
     }
+    set_chunk_state(
+        &mut *query_state.chunks.lock().await,
+        chunk,
+        ChunkState::Collecting {
+            task: task_id.to_string(),
+        },
+    );
 
-    info!("Posted task {}", task.id);
+    let deadline = Instant::now() + CONFIG.result_deadline.unwrap_or(params.ttl);
+    let mut collected = get_results(query_state.clone(), query, task_id, &posted, deadline).await;
+    let missing: Vec<Site> = posted
+        .into_iter()
+        .filter(|site| !collected.answered.contains(site))
+        .collect();
+    let mut timed_out = Vec::new();
+    let failures = missing
+        .iter()
+        .map(|site| match collected.errors.remove(site) {
+            Some(error) => (site.clone(), Failure::Error(error)),
+            None => {
+                timed_out.push(site.clone());
+                (site.clone(), Failure::TimedOut)
+            }
+        })
+        .collect();
+    if !timed_out.is_empty() {
+        warn!("Sites {timed_out:?} didn't answer task {task_id} before the collection deadline");
+    }
+    let state = ChunkState::Done {
+        task: task_id.to_string(),
+        answered: collected.answered,
+        missing,
+        timed_out,
+    };
+    record_failures(&query_state, failures).await;
+    release_chunk(&query_state, chunk).await;
+    set_chunk_state(&mut *query_state.chunks.lock().await, chunk, state); // once everything else about it is recorded
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = get_results(shared_state, task.id, wait_count).await {
-            warn!("Failed to get results for {}: {e}", task.id);
-        }
-    });
+/// Sites of a chunk are no longer in flight and can be queried again
+async fn release_chunk(query_state: &QueryState, chunk: u64) {
+    query_state
+        .sites_in_flight
+        .lock()
+        .await
+        .retain(|_, in_flight| in_flight.chunk != chunk);
+}
 
-    Ok(())
+/// Some of the sites of a chunk are no longer in flight, the others still are
+async fn release_sites(query_state: &QueryState, chunk: u64, sites: &[Site]) {
+    query_state
+        .sites_in_flight
+        .lock()
+        .await
+        .retain(|site, in_flight| in_flight.chunk != chunk || !sites.contains(site));
 }
 
-async fn query_sites(
-    shared_state: SharedState,
-    sites: Option<&[String]>,
-) -> Result<(), PrismError> {
-    match sites {
-        Some(sites) => {
-            // argument site is present, Prism uses it and ignores sites from the shared state
-            post_query(shared_state, sites.to_vec()).await?;
-        }
-        None => {
-            // Prism queries sites from the shared state
-            let mut locked_sites = shared_state.sites_to_query.lock().await;
-            let sites: Vec<String> = locked_sites.clone().into_iter().collect();
-            if sites.is_empty() {
-                return Ok(());
+/// Records that sites failed to answer, each is queried again once its backoff has passed
+async fn record_failures(query_state: &QueryState, failures: Vec<(Site, Failure)>) {
+    let mut site_outcomes = query_state.site_outcomes.lock().await;
+    for (site, failure) in failures {
+        let outcome = site_outcomes.entry(site.clone()).or_default();
+        let delay = outcome.fail(failure, &CONFIG.site_retry_backoff);
+        info!(
+            "Site {} failed {} times in a row, querying it again in {:?}",
+            site, outcome.consecutive_failures, delay
+        );
+        let query_state = query_state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let still_failing = query_state
+                .site_outcomes
+                .lock()
+                .await
+                .get(&site)
+                .is_some_and(|outcome| outcome.is_failing());
+            if still_failing && query_state.sites().contains(&site) {
+                queue_sites(&query_state, vec![site]).await;
             }
-            post_query(shared_state.clone(), sites).await?;
-            locked_sites.clear(); // if posting the task was successful, the set of sites to query is emptied
+        });
+    }
+}
+
+/// Puts sites of a chunk back into the set of sites to query, they are tried again with the next refresh
+async fn requeue_sites(query_state: &QueryState, chunk: u64, sites: Vec<Site>) {
+    let mut sites_to_query = query_state.sites_to_query.lock().await;
+    let mut sites_in_flight = query_state.sites_in_flight.lock().await;
+    for site in sites {
+        if sites_in_flight
+            .get(&site)
+            .is_some_and(|in_flight| in_flight.chunk == chunk)
+        {
+            sites_in_flight.remove(&site);
         }
-    };
+        sites_to_query.insert(site);
+    }
+}
 
-    Ok(())
+/// Posts a task to the sites, returns the sites Beam rejected as invalid receivers, and the id of the task and the sites it was actually sent to, none if all of them were rejected
+async fn post_task(
+    query_state: &QueryState,
+    query: &NamedQuery,
+    params: &TaskParams,
+    sites: Vec<Site>,
+) -> (Vec<Site>, Result<Option<(MsgId, Vec<Site>)>, PrismError>) {
+    let site_display = sites.join(", ");
+    let project = query_state.project();
+    let mut task = create_beam_task(&project, query, params, sites);
+    info!(
+        "Querying sites {:?} for project {} with query {}",
+        site_display, project.name, query_state.name
+    );
+
+    let mut rejected = Vec::new();
+    loop {
+        match query_state.beam.post_task(&task).await {
+            Ok(()) => break,
+            Err(beam_lib::BeamError::InvalidReceivers(invalid))
+                if task.to.iter().any(|t| invalid.contains(&t.proxy_id())) =>
+            {
+                // posted again without them, until Beam accepts the task or no receiver is left
+                let (invalid, valid) = task
+                    .to
+                    .into_iter()
+                    .partition(|t| invalid.contains(&t.proxy_id()));
+                task.to = valid;
+                rejected.extend(invalid.iter().map(site_of));
+                if task.to.is_empty() {
+                    return (rejected, Ok(None));
+                }
+            }
+            Err(e) => {
+                let error = PrismError::BeamError(format!("Unable to post a query: {}", e));
+                return (rejected, Err(error));
+            }
+        }
+    }
+
+    info!("Posted task {}", task.id);
+    metrics::BEAM_TASKS_POSTED.inc();
+
+    let sites: Vec<Site> = task.to.iter().map(site_of).collect();
+    (rejected, Ok(Some((task.id, sites))))
+}
+
+/// Site name of an app's long name, {app}.{site}.{broker}
+fn site_of(app_id: &AppId) -> Site {
+    app_id.as_ref().split('.').nth(1).unwrap().to_string()
+}
+
+/// Queries the sites from the shared state
+async fn query_sites(query_state: QueryState) {
+    let mut locked_sites = query_state.sites_to_query.lock().await;
+    if locked_sites.is_empty() {
+        return;
+    }
+    let sites: Vec<Site> = locked_sites.drain().collect();
+    post_query(&query_state, sites).await; // sites of chunks that fail to be posted are put back
+}
+
+/// Sites that answered a task, and the errors sent by those that failed
+#[derive(Debug, Default)]
+struct CollectedResults {
+    answered: Vec<Site>,
+    errors: HashMap<Site, String>,
+    failed: HashSet<Site>, // failed for good, not waited for any more, unlike sites that failed temporarily
+    statuses: HashMap<Site, beam_lib::WorkStatus>, // latest status of each site, a result with the same status again is skipped
+}
+
+impl CollectedResults {
+    fn is_final(&self, site: &Site) -> bool {
+        self.answered.contains(site) || self.failed.contains(site)
+    }
+
+    /// The site's result is an error that another result won't fix
+    fn fail(&mut self, site: Site, error: String) {
+        self.failed.insert(site.clone());
+        self.errors.insert(site, error);
+    }
 }
 
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Collects the results of a task until all the sites answered or the deadline passed, reconnecting if the result stream breaks off
 async fn get_results(
-    shared_state: SharedState,
+    query_state: QueryState,
+    query: Arc<NamedQuery>, // as sent in the task
     task_id: MsgId,
-    wait_count: usize,
-) -> Result<(), PrismError> {
-    let resp = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("v1/tasks/{}/results?wait_count={}", task_id, wait_count),
-        )
-        .header(
-            header::ACCEPT,
-            HeaderValue::from_static("text/event-stream"),
-        )
-        .send()
-        .await
-        .map_err(|e| PrismError::BeamError(e.to_string()))?;
-
-    let code = resp.status();
-    if !code.is_success() {
-        return Err(PrismError::BeamError(
-            resp.text().await.unwrap_or_else(|e| e.to_string()),
-        ));
-    }
-    let mut stream = async_sse::decode(
-        resp.bytes_stream()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            .into_async_read(),
-    );
-    while let Some(Ok(async_sse::Event::Message(msg))) = stream.next().await {
-        let (from, measure_report) = match decode_result(&msg) {
-            Ok(v) => v,
-            Err(PrismError::UnexpectedWorkStatus(beam_lib::WorkStatus::Claimed)) => {
-                info!("Task claimed");
-                continue;
+    sites: &[Site],
+    deadline: Instant,
+) -> CollectedResults {
+    let mut collected = CollectedResults::default();
+    let mut reconnect_delay = Duration::from_secs(1);
+    let mut polling = CONFIG.result_mode == ResultMode::Poll;
+    loop {
+        let waiting = sites
+            .iter()
+            .filter(|site| !collected.is_final(site))
+            .count();
+        let mut streamed = false; // whether the result stream delivered an event before it failed
+                                  // Beam counts all the results of the task, also those collected before, so it waits for all the sites, not just the remaining ones
+        let read = async {
+            if polling {
+                let wait_time = CONFIG
+                    .result_poll_wait
+                    .min(deadline.saturating_duration_since(Instant::now()));
+                poll_results(
+                    &query_state,
+                    &query,
+                    task_id,
+                    sites.len(),
+                    wait_time,
+                    &mut collected,
+                )
+                .await
+            } else {
+                stream_results(
+                    &query_state,
+                    &query,
+                    task_id,
+                    sites.len(),
+                    &mut collected,
+                    &mut streamed,
+                )
+                .await
             }
-            Err(PrismError::UnexpectedWorkStatus(
-                beam_lib::WorkStatus::PermFailed | beam_lib::WorkStatus::TempFailed,
-            )) => {
-                warn!("WorkStatus PermFailed: {msg:?}");
-                continue;
+        };
+        let result = tokio::time::timeout_at(deadline.into(), read).await;
+        let remaining = sites
+            .iter()
+            .filter(|site| !collected.is_final(site))
+            .count();
+        if remaining < waiting {
+            reconnect_delay = Duration::from_secs(1);
+        }
+        let pause = match result {
+            // Beam answers a poll right away once every site sent some result, even if it is just claimed
+            Ok(Ok(())) if polling => remaining == waiting,
+            Ok(Ok(())) => {
+                debug!("Result stream of task {task_id} ended");
+                true
             }
-            Err(e) => {
-                warn!("Failed to deserialize message {msg:?} into a result: {e}");
-                continue;
+            Ok(Err(e)) => {
+                warn!("Failed to get results for {task_id}: {e}");
+                // a stream that broke off after delivering results is reconnected, one that fails right away may be stripped by a proxy in between
+                if !polling && !streamed && CONFIG.result_mode == ResultMode::Auto {
+                    info!("Falling back to polling for the results of task {task_id}");
+                    polling = true;
+                    false
+                } else {
+                    true
+                }
             }
+            Err(_) => break, // deadline passed
         };
-        let criteria = match extract_criteria(measure_report) {
-            Ok(c) => c,
-            Err(e) => {
-                warn!("Failed to extract criteria from {from}: {e}");
-                continue;
+        if remaining == 0 {
+            break;
+        }
+        if pause {
+            if Instant::now() + reconnect_delay >= deadline {
+                break;
             }
-        };
-        shared_state.criteria_cache.lock().await.cache.insert(
-            //if successful caching the criteria
-            from.as_ref().split('.').nth(1).unwrap().to_string(), // extracting site name from app long name
-            (criteria, std::time::SystemTime::now()),
-        );
-        info!(
-            "Cached results from site {} for task {}",
-            from.as_ref().split('.').nth(1).unwrap().to_string(),
-            task_id
-        );
+            info!("Asking for the results of task {task_id} again in {reconnect_delay:?}, waiting for {remaining} more");
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+    collected
+}
+
+/// Reads the results of a task as Server-Sent Events, streamed tells whether any event was read
+async fn stream_results(
+    query_state: &QueryState,
+    query: &NamedQuery,
+    task_id: MsgId,
+    wait_count: usize,
+    collected: &mut CollectedResults,
+    streamed: &mut bool,
+) -> Result<(), PrismError> {
+    let mut stream = query_state.beam.stream_results(task_id, wait_count).await?;
+    while let Some(event) = stream.next().await {
+        handle_event(query_state, query, task_id, event?, collected).await;
+        *streamed = true;
+    }
+    Ok(())
+}
+
+/// Long-polls the results of a task, Beam answers once wait_count results are there or wait_time passed
+async fn poll_results(
+    query_state: &QueryState,
+    query: &NamedQuery,
+    task_id: MsgId,
+    wait_count: usize,
+    wait_time: Duration,
+    collected: &mut CollectedResults,
+) -> Result<(), PrismError> {
+    let events = query_state
+        .beam
+        .poll_results(task_id, wait_count, wait_time)
+        .await?;
+    for event in events {
+        handle_event(query_state, query, task_id, event, collected).await;
     }
     Ok(())
 }
 
-fn decode_result(msg: &async_sse::Message) -> Result<(AppId, MeasureReport), PrismError> {
-    let result: TaskResult<RawString> =
-        serde_json::from_slice(msg.data()).map_err(PrismError::DeserializationError)?;
+async fn handle_event(
+    query_state: &QueryState,
+    query: &NamedQuery,
+    task_id: MsgId,
+    event: ResultEvent,
+    collected: &mut CollectedResults,
+) {
+    match event {
+        ResultEvent::Result(result) => {
+            handle_result(query_state, query, task_id, result, collected).await
+        }
+        ResultEvent::Malformed(e) => {
+            metrics::RESULTS_REJECTED
+                .with_label_values(&["decode"])
+                .inc();
+            warn!("Failed to deserialize a message into a result: {e}");
+        }
+    }
+}
+
+/// Caches the criteria in a result, or records why the site failed. Results already collected, which Beam sends again after reconnecting or with every poll, are skipped
+async fn handle_result(
+    query_state: &QueryState,
+    query: &NamedQuery,
+    task_id: MsgId,
+    result: TaskResult<RawString>,
+    collected: &mut CollectedResults,
+) {
+    let Some(site) = result.from.as_ref().split('.').nth(1).map(str::to_string) else {
+        // extracting site name from app long name
+        warn!("Result from unexpected sender {}", result.from);
+        return;
+    };
+    if collected.is_final(&site) || collected.statuses.get(&site) == Some(&result.status) {
+        debug!("Already collected the result of site {site} for task {task_id}, skipping it");
+        return;
+    }
+    collected.statuses.insert(site.clone(), result.status);
     match result.status {
-        beam_lib::WorkStatus::Succeeded => {}
-        yep => {
-            // claimed not an error!!!!
-            return Err(PrismError::UnexpectedWorkStatus(yep));
+        beam_lib::WorkStatus::Succeeded => metrics::count_result(result.status),
+        beam_lib::WorkStatus::Claimed => {
+            metrics::count_result(result.status);
+            info!("Task claimed");
+            return;
+        }
+        status @ (beam_lib::WorkStatus::PermFailed | beam_lib::WorkStatus::TempFailed) => {
+            metrics::count_result(status);
+            warn!("WorkStatus {status:?} from site {site}: {}", result.body.0);
+            if status == beam_lib::WorkStatus::PermFailed {
+                collected.failed.insert(site.clone());
+            }
+            collected
+                .errors
+                .insert(site, format!("{status:?}: {}", result.body.0));
+            return;
+        }
+    }
+    let measure_report = match decode_result(result) {
+        Ok(measure_report) => measure_report,
+        Err(e) => {
+            metrics::RESULTS_REJECTED
+                .with_label_values(&["decode"])
+                .inc();
+            warn!("Failed to decode the result from site {site}: {e}");
+            collected.fail(site, format!("undecodable result: {e}"));
+            return;
+        }
+    };
+    let criteria = match extract_criteria(measure_report) {
+        Ok(c) => {
+            let project = query_state.project();
+            roll_up(normalize(c, &project.mapping), &project.hierarchies)
+        }
+        Err(e) => {
+            metrics::RESULTS_REJECTED
+                .with_label_values(&["extract"])
+                .inc();
+            warn!("Failed to extract criteria from {site}: {e}");
+            collected.fail(site, format!("undecodable result: {e}"));
+            return;
         }
+    };
+    if query_state.query().body != query.body {
+        info!(
+            "Query {} changed since task {} was sent, discarding results from site {}",
+            query.name, task_id, site
+        );
+        return;
     }
+    query_state.criteria_cache.lock().await.cache.insert(
+        //if successful caching the criteria
+        site.clone(),
+        (criteria, std::time::SystemTime::now()),
+    );
+    metrics::RESULTS_CACHED.inc();
+    info!("Cached results from site {} for task {}", site, task_id);
+    query_state
+        .site_outcomes
+        .lock()
+        .await
+        .entry(site.clone())
+        .or_default()
+        .succeed();
+    collected.answered.push(site.clone());
+    let _ = query_state.cache_updates.send(site); // only fails if nobody is listening
+}
+
+fn decode_result(result: TaskResult<RawString>) -> Result<MeasureReport, PrismError> {
     let decoded = BASE64
         .decode(result.body.0)
         .map_err(PrismError::DecodeError)?;
-    Ok((
-        result.from,
-        serde_json::from_slice(&decoded).map_err(PrismError::DeserializationError)?,
-    ))
+    serde_json::from_slice(&decoded).map_err(PrismError::DeserializationError)
 }
 
-async fn wait_for_beam_proxy() -> beam_lib::Result<()> {
-    const MAX_RETRIES: u8 = 10;
-    let mut tries = 1;
+const MAX_PROBE_DELAY: Duration = Duration::from_secs(30);
+
+/// Probes the Beam proxy until it is healthy, waiting twice as long after every failure
+async fn wait_for_beam_proxy(beam: &dyn BeamTransport) {
+    let mut probe_delay = Duration::from_secs(1);
     loop {
-        match reqwest::get(format!("{}v1/health", CONFIG.beam_proxy_url)).await {
-            //FIXME why doesn't it work with url from config
-            Ok(res) if res.status() == reqwest::StatusCode::OK => return Ok(()),
-            _ if tries <= MAX_RETRIES => tries += 1,
-            Err(e) => return Err(e.into()),
-            Ok(res) => {
-                return Err(beam_lib::BeamError::Other(
-                    format!("Proxy reachable but failed to start {}", res.status()).into(),
-                ));
-            }
+        match beam.check_proxy().await {
+            Ok(()) => return,
+            Err(e) => warn!("Beam proxy isn't reachable, probing it again in {probe_delay:?}: {e}"),
         }
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::time::sleep(probe_delay).await;
+        probe_delay = (probe_delay * 2).min(MAX_PROBE_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collected(site: &str, criteria: &str) -> CollectedCriteria {
+        let criteria: Stratifiers = serde_json::from_str(criteria).unwrap();
+        CollectedCriteria {
+            site_criteria: [(site.to_string(), (criteria, std::time::SystemTime::now()))].into(),
+            missing_sites: vec![],
+            stale_sites: vec![],
+        }
+    }
+
+    #[test]
+    fn test_merge_queries() {
+        // both queries return gender, its counts are taken from the first query instead of being added up
+        let core = collected(
+            "proxy1",
+            r#"{"gender":{"female":20,"male":10},"age":{"40":30}}"#,
+        );
+        let biospecimen = collected(
+            "proxy1",
+            r#"{"gender":{"female":20,"male":10},"sample_kind":{"blood":5}}"#,
+        );
+        let mut other_site = collected("proxy2", r#"{"gender":{"female":1}}"#);
+        other_site.missing_sites.push("proxy3".into());
+
+        let merged = core.merge(biospecimen).merge(other_site);
+
+        pretty_assertions::assert_eq!(
+            r#"{"age":{"40":30},"gender":{"female":20,"male":10},"sample_kind":{"blood":5}}"#,
+            serde_json::to_string(&merged.site_criteria["proxy1"].0).unwrap()
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"age":{"40":30},"gender":{"female":21,"male":10},"sample_kind":{"blood":5}}"#,
+            serde_json::to_string(&merged.combined(&Hierarchies::new())).unwrap()
+        );
+        assert_eq!(vec!["proxy3"], merged.missing_sites);
+    }
+
+    #[test]
+    fn test_restoring_outdated_caches() {
+        let project = &CONFIG.projects["bbmri"];
+        let criteria_cache = |fingerprint: Option<String>| CriteriaCache {
+            cache: [(
+                "proxy1".into(),
+                (Stratifiers::new(), std::time::SystemTime::now()),
+            )]
+            .into(),
+            fingerprint,
+        };
+        let restored = |criteria_cache: CriteriaCache| {
+            let project_state = ProjectState::new(
+                project.clone(),
+                [("default".into(), criteria_cache)].into(),
+                Arc::new(fake_beam::FakeBeam::default()),
+                DiscoveredSites::default(),
+            );
+            let criteria_cache = project_state.queries["default"].criteria_cache.clone();
+            let restored = criteria_cache.try_lock().unwrap().cache.len();
+            restored
+        };
+
+        assert_eq!(
+            1,
+            restored(criteria_cache(Some(project.fingerprint("default"))))
+        );
+        // saved with another body, mapping or hierarchy, or before caches had fingerprints
+        assert_eq!(0, restored(criteria_cache(Some("outdated".into()))));
+        assert_eq!(0, restored(criteria_cache(None)));
     }
 }
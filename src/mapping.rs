@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    criteria::{combine_criteria_groups, combine_maps, Criteria, Stratifiers},
+    errors::PrismError,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Case {
+    Lower,
+    Upper,
+}
+
+/// How a stratifier and its values are normalized
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StratifierRule {
+    /// New name of the stratifier, it is merged with a stratifier already having that name
+    #[serde(default)]
+    rename: Option<String>,
+    /// Leaves the stratifier out entirely
+    #[serde(default)]
+    drop: bool,
+    /// Converts the values to lower or upper case before they are mapped
+    #[serde(default)]
+    case: Option<Case>,
+    /// New names of values, values mapped to the same name are merged, values mapped to null are left out
+    #[serde(default)]
+    values: BTreeMap<String, Option<String>>,
+}
+
+pub type Mapping = BTreeMap<String, StratifierRule>; // by stratifier name as sent by the sites
+
+pub fn load_mapping(path: &Path) -> Result<Mapping, PrismError> {
+    let mapping = fs::read_to_string(path).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "Mapping file {} can't be read: {e}",
+            path.display()
+        ))
+    })?;
+    serde_json::from_str(&mapping).map_err(|e| {
+        PrismError::ConfigError(format!("Mapping file {} is invalid: {e}", path.display()))
+    })
+}
+
+/// Renames, merges and drops stratifiers and their values, so that sites with slightly different vocabularies add up correctly
+pub fn normalize(stratifiers: Stratifiers, mapping: &Mapping) -> Stratifiers {
+    let mut normalized = Stratifiers::new();
+    for (key, criteria) in stratifiers {
+        let (key, criteria) = match mapping.get(&key) {
+            Some(rule) if rule.drop => continue,
+            Some(rule) => (
+                rule.rename.clone().unwrap_or(key),
+                normalize_criteria(criteria, rule),
+            ),
+            None => (key, criteria),
+        };
+        normalized = combine_criteria_groups(normalized, [(key, criteria)].into());
+    }
+    normalized
+}
+
+fn normalize_criteria(criteria: Criteria, rule: &StratifierRule) -> Criteria {
+    let mut normalized = Criteria::new();
+    for (value, count) in criteria {
+        let value = match rule.case {
+            Some(Case::Lower) => value.to_lowercase(),
+            Some(Case::Upper) => value.to_uppercase(),
+            None => value,
+        };
+        let value = match rule.values.get(&value) {
+            Some(Some(mapped)) => mapped.clone(),
+            Some(None) => continue,
+            None => value,
+        };
+        normalized = combine_maps(normalized, [(value, count)].into());
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let mapping: Mapping = serde_json::from_str(
+            r#"{
+                "MedicationType": {"values": {"null": null}},
+                "Gender": {"rename": "gender", "case": "lower", "values": {"m": "male"}},
+                "internal": {"drop": true}
+            }"#,
+        )
+        .unwrap();
+        let stratifiers: Stratifiers = [
+            (
+                "MedicationType".into(),
+                [("CH".into(), 5), ("null".into(), 2)].into(),
+            ),
+            (
+                "Gender".into(),
+                [("Male".into(), 5), ("M".into(), 2), ("female".into(), 3)].into(),
+            ),
+            ("gender".into(), [("male".into(), 1)].into()),
+            ("internal".into(), [("x".into(), 1)].into()),
+        ]
+        .into();
+
+        let normalized = normalize(stratifiers, &mapping);
+
+        pretty_assertions::assert_eq!(
+            r#"{"MedicationType":{"CH":5},"gender":{"female":3,"male":8}}"#,
+            serde_json::to_string(&normalized).unwrap()
+        );
+    }
+}
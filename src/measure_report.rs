@@ -144,7 +144,7 @@ mod test {
     #[test]
     fn test_extract_criteria_bbmri() {
         let measure_report: MeasureReport =
-            serde_json::from_str(&EXAMPLE_MEASURE_REPORT_BBMRI).expect("Can't be deserialized");
+            serde_json::from_str(EXAMPLE_MEASURE_REPORT_BBMRI).expect("Can't be deserialized");
 
         let stratifiers =
             extract_criteria(measure_report).expect("what, no proper criteria groups");
@@ -157,7 +157,7 @@ mod test {
     #[test]
     fn test_extract_criteria_dktk() {
         let measure_report: MeasureReport =
-            serde_json::from_str(&EXAMPLE_MEASURE_REPORT_DKTK).expect("Can't be deserialized");
+            serde_json::from_str(EXAMPLE_MEASURE_REPORT_DKTK).expect("Can't be deserialized");
 
         let stratifiers =
             extract_criteria(measure_report).expect("what, no proper criteria groups");
@@ -0,0 +1,134 @@
+use std::time::{Instant, SystemTime};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::SharedState;
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prism_http_requests_total",
+        "HTTP requests handled, by route and response status",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "prism_http_request_duration_seconds",
+        "HTTP request latencies, by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+pub static BEAM_TASKS_POSTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "prism_beam_tasks_posted_total",
+        "Tasks successfully posted to beam"
+    )
+    .unwrap()
+});
+
+pub static BEAM_RESULTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prism_beam_results_total",
+        "Results received from beam, by WorkStatus",
+        &["status"]
+    )
+    .unwrap()
+});
+
+pub static RESULTS_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prism_results_rejected_total",
+        "Results that couldn't be cached, by the step that rejected them (decode or extract)",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static RESULTS_CACHED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("prism_results_cached_total", "Results successfully cached").unwrap()
+});
+
+static SITE_CACHE_AGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prism_site_cache_age_seconds",
+        "Age of the cached criteria, by project, query and site",
+        &["project", "query", "site"]
+    )
+    .unwrap()
+});
+
+static SITES_TO_QUERY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prism_sites_to_query",
+        "Sites waiting for the next task to be sent, by project and query",
+        &["project", "query"]
+    )
+    .unwrap()
+});
+
+pub fn count_result(status: beam_lib::WorkStatus) {
+    BEAM_RESULTS
+        .with_label_values(&[format!("{status:?}")])
+        .inc();
+}
+
+/// Middleware counting requests and measuring their latency
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".into());
+    let start = Instant::now();
+    let response = next.run(req).await;
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[route.as_str(), response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Metrics in Prometheus text format, gauges are computed from the shared state at the time of scraping
+pub async fn handle_metrics(State(shared_state): State<SharedState>) -> Response {
+    SITE_CACHE_AGE.reset(); // forget sites no longer in the cache
+    for (name, project_state) in shared_state.projects.iter() {
+        for (query, query_state) in &project_state.queries {
+            {
+                let criteria_cache = query_state.criteria_cache.lock().await;
+                for (site, (_, created)) in &criteria_cache.cache {
+                    let age = SystemTime::now()
+                        .duration_since(*created)
+                        .unwrap_or_default()
+                        .as_secs();
+                    SITE_CACHE_AGE
+                        .with_label_values(&[name, query, site])
+                        .set(age as i64);
+                }
+            }
+            SITES_TO_QUERY
+                .with_label_values(&[name, query])
+                .set(query_state.sites_to_query.lock().await.len() as i64);
+        }
+    }
+
+    match TextEncoder::new().encode_to_string(&prometheus::gather()) {
+        Ok(metrics) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
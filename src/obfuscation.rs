@@ -0,0 +1,157 @@
+use std::time::UNIX_EPOCH;
+
+use hmac::{Hmac, Mac};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha20Rng,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::{Created, Site},
+    criteria::Stratifiers,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ObfuscationMethod {
+    /// Counts are returned as they are
+    None,
+    /// Counts are rounded to the nearest multiple of the rounding step
+    Rounding,
+    /// Laplace noise is added to the counts
+    Laplace,
+}
+
+#[derive(Debug, Clone)]
+pub enum Obfuscation {
+    None,
+    Rounding { step: u64 },
+    Laplace { epsilon: f64, secret: String },
+}
+
+/// Identifies the cached criteria a count was added up from, it changes whenever one of the sites' criteria is cached again.
+/// It is hashed with a fixed algorithm, as the noise derived from it must stay the same across restarts and Rust releases.
+pub fn generation<'a>(cached: impl IntoIterator<Item = (&'a Site, &'a Created)>) -> u64 {
+    let mut hasher = Sha256::new();
+    for (site, created) in cached {
+        let since_epoch = created.duration_since(UNIX_EPOCH).unwrap_or_default();
+        hasher.update((site.len() as u64).to_be_bytes());
+        hasher.update(site.as_bytes());
+        hasher.update(since_epoch.as_secs().to_be_bytes());
+        hasher.update(since_epoch.subsec_nanos().to_be_bytes());
+    }
+    let hash = hasher.finalize();
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Obfuscates added up counts, so that the sum of many sites' counts doesn't look more precise than it is.
+/// The noise of each count is derived from the secret, the criterion and the generation, so repeated requests return the same value and averaging them doesn't reveal the true count.
+pub fn obfuscate(
+    stratifiers: Stratifiers,
+    obfuscation: &Obfuscation,
+    generation: u64,
+) -> Stratifiers {
+    match obfuscation {
+        Obfuscation::None => stratifiers,
+        Obfuscation::Rounding { step } => {
+            map_counts(stratifiers, |_, _, count| (count + step / 2) / step * step)
+        }
+        Obfuscation::Laplace { epsilon, secret } => {
+            map_counts(stratifiers, |stratifier, stratum, count| {
+                let mut rng = noise_rng(secret, generation, stratifier, stratum);
+                let noise = laplace(&mut rng, 1.0 / epsilon).round() as i64;
+                count.saturating_add_signed(noise)
+            })
+        }
+    }
+}
+
+/// Random numbers for the noise of one count, seeded with an HMAC-SHA256 of the count's generation and criterion, so they never change for the same secret
+fn noise_rng(secret: &str, generation: u64, stratifier: &str, stratum: &str) -> ChaCha20Rng {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(&generation.to_be_bytes());
+    for part in [stratifier, stratum] {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part.as_bytes());
+    }
+    ChaCha20Rng::from_seed(mac.finalize().into_bytes().into())
+}
+
+fn map_counts(stratifiers: Stratifiers, f: impl Fn(&str, &str, u64) -> u64) -> Stratifiers {
+    stratifiers
+        .into_iter()
+        .map(|(stratifier, criteria)| {
+            let criteria = criteria
+                .into_iter()
+                .map(|(stratum, count)| {
+                    let count = f(&stratifier, &stratum, count);
+                    (stratum, count)
+                })
+                .collect();
+            (stratifier, criteria)
+        })
+        .collect()
+}
+
+/// Samples the Laplace distribution centered at 0 by inverting its CDF
+fn laplace(rng: &mut impl RngCore, scale: f64) -> f64 {
+    let u = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 - 0.5; // uniform in [-0.5, 0.5), without relying on how rand converts to floats
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::criteria::Criteria;
+
+    fn stratifiers() -> Stratifiers {
+        let criteria: Criteria = [
+            ("female".into(), 14),
+            ("male".into(), 25),
+            ("other".into(), 4),
+        ]
+        .into();
+        [("gender".into(), criteria)].into()
+    }
+
+    #[test]
+    fn test_rounding() {
+        let rounded = obfuscate(stratifiers(), &Obfuscation::Rounding { step: 10 }, 0);
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":10,"male":30,"other":0}}"#,
+            serde_json::to_string(&rounded).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_laplace_is_repeatable() {
+        let obfuscation = Obfuscation::Laplace {
+            epsilon: 0.1,
+            secret: "secret".into(),
+        };
+        let first = obfuscate(stratifiers(), &obfuscation, 1);
+        let second = obfuscate(stratifiers(), &obfuscation, 1);
+        pretty_assertions::assert_eq!(first, second);
+
+        // with epsilon 0.1 all the counts staying the same in a new generation is very unlikely
+        let next_generation = obfuscate(stratifiers(), &obfuscation, 2);
+        assert_ne!(first, next_generation);
+    }
+
+    #[test]
+    fn test_laplace_is_stable() {
+        // the noise must never change for the same secret and cached criteria, or averaging the values before and after an update would remove it
+        let created = UNIX_EPOCH + std::time::Duration::new(1_760_000_000, 123_456_789);
+        let generation = generation([(&"proxy1".to_string(), &created)]);
+        assert_eq!(18068278468641016572, generation);
+        let obfuscation = Obfuscation::Laplace {
+            epsilon: 0.1,
+            secret: "secret".into(),
+        };
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":6,"male":17,"other":0}}"#,
+            serde_json::to_string(&obfuscate(stratifiers(), &obfuscation, generation)).unwrap()
+        );
+    }
+}
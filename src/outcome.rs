@@ -0,0 +1,126 @@
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SiteState {
+    #[default]
+    Unknown, // not queried yet
+    Querying,
+    Succeeded,
+    Failing,
+    TimedOut, // didn't answer before the collection deadline
+}
+
+/// Why a site didn't deliver results
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    Error(String), // body of a failed result, or why there was none
+    TimedOut,
+}
+
+/// Exponential backoff for querying failing sites again
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Delay after the given number of consecutive failures, doubled with every failure
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// What became of the latest queries to a site
+#[derive(Debug, Clone, Default)]
+pub struct SiteOutcome {
+    pub state: SiteState,
+    pub last_attempt: Option<SystemTime>,
+    pub last_success: Option<SystemTime>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub retry_at: Option<SystemTime>, // the site isn't queried again before
+}
+
+impl SiteOutcome {
+    /// A task was sent to the site
+    pub fn attempt(&mut self) {
+        self.state = SiteState::Querying;
+        self.last_attempt = Some(SystemTime::now());
+    }
+
+    /// Results from the site were cached
+    pub fn succeed(&mut self) {
+        self.state = SiteState::Succeeded;
+        self.last_success = Some(SystemTime::now());
+        self.consecutive_failures = 0;
+        self.last_error = None;
+        self.retry_at = None;
+    }
+
+    /// The site failed to answer, returns how long to wait before querying it again
+    pub fn fail(&mut self, failure: Failure, backoff: &Backoff) -> Duration {
+        let (state, error) = match failure {
+            Failure::Error(error) => (SiteState::Failing, error),
+            Failure::TimedOut => (
+                SiteState::TimedOut,
+                "No results before the collection deadline".to_string(),
+            ),
+        };
+        self.state = state;
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+        let delay = backoff.delay(self.consecutive_failures);
+        self.retry_at = Some(SystemTime::now() + delay);
+        delay
+    }
+
+    pub fn is_failing(&self) -> bool {
+        matches!(self.state, SiteState::Failing | SiteState::TimedOut)
+    }
+
+    pub fn is_backing_off(&self) -> bool {
+        self.retry_at
+            .is_some_and(|retry_at| retry_at > SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(30),
+            max: Duration::from_secs(300),
+        };
+        let mut outcome = SiteOutcome::default();
+        outcome.attempt();
+        assert_eq!(
+            Duration::from_secs(30),
+            outcome.fail(Failure::Error("PermFailed".into()), &backoff)
+        );
+        assert_eq!(
+            Duration::from_secs(60),
+            outcome.fail(Failure::TimedOut, &backoff)
+        );
+        assert_eq!(SiteState::TimedOut, outcome.state);
+        assert!(outcome.is_backing_off());
+        assert!(outcome.is_failing());
+        assert_eq!(Duration::from_secs(300), backoff.delay(5));
+        assert_eq!(Duration::from_secs(300), backoff.delay(100));
+
+        outcome.succeed();
+        assert_eq!(0, outcome.consecutive_failures);
+        assert!(!outcome.is_backing_off());
+        assert_eq!(
+            Duration::from_secs(30),
+            outcome.fail(Failure::Error("no answer".into()), &backoff)
+        );
+    }
+}
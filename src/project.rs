@@ -0,0 +1,227 @@
+use std::{collections::BTreeMap, fs, path::Path, path::PathBuf, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{
+    beam::{TaskOverrides, TaskParams},
+    body::{load_body, pretty_ast},
+    cache::CRITERIACACHE_TTL,
+    errors::PrismError,
+    hierarchy::{load_hierarchies, Hierarchies},
+    mapping::{load_mapping, Mapping},
+};
+
+pub const DEFAULT_QUERY: &str = "default"; // name of the query of a project defined by its body file only
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A project Prism serves criteria for, with its own sites, queries and cache
+#[derive(Debug)]
+pub struct Project {
+    pub name: String,
+    pub sites: Vec<String>,
+    pub queries: BTreeMap<String, Arc<NamedQuery>>,
+    pub target_app: String,
+    pub hierarchies: Hierarchies,
+    pub mapping: Mapping,
+    pub task_params: TaskParams,
+    pub site_task_params: BTreeMap<String, TaskParams>, // overriding the project's for some sites
+    pub discovery: bool, // whether the discovered sites are queried in addition to the configured ones
+    pub files: Vec<PathBuf>, // body, hierarchy and mapping files the project was loaded from, watched for changes
+}
+
+pub type Projects = BTreeMap<String, Arc<Project>>; // by project name
+
+/// One of the query bodies sent to the sites of a project, its criteria are cached separately
+#[derive(Debug)]
+pub struct NamedQuery {
+    pub name: String,
+    pub body: String,
+    pub refresh_interval: Duration, // how often sites with missing or expired criteria are queried without Lens asking for them
+    pub ttl: Duration,
+}
+
+/// A named query as defined in the projects file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QueryDefinition {
+    pub body_file: PathBuf,
+    /// In seconds, defaults to 15 minutes
+    #[serde(default)]
+    pub refresh_interval: Option<u64>,
+    /// In seconds, defaults to 2 hours
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// A project as defined in the projects file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectDefinition {
+    #[serde(default)]
+    pub sites: Vec<String>,
+    /// Defaults to --target-app
+    #[serde(default)]
+    pub target_app: Option<String>,
+    /// Defaults to resources/body_{project}.json, only used if no queries are given
+    #[serde(default)]
+    pub body_file: Option<PathBuf>,
+    #[serde(default)]
+    pub queries: BTreeMap<String, QueryDefinition>,
+    #[serde(default)]
+    pub hierarchy_file: Option<PathBuf>,
+    #[serde(default)]
+    pub mapping_file: Option<PathBuf>,
+    /// Overrides the task parameters given on the command line
+    #[serde(default)]
+    pub task: TaskOverrides,
+    /// Overrides the task parameters of the project for single sites
+    #[serde(default)]
+    pub site_tasks: BTreeMap<String, TaskOverrides>,
+    /// Whether the sites discovered with --discovery-url are queried too, not by default
+    #[serde(default)]
+    pub discovery: bool,
+}
+
+impl Project {
+    pub fn load(
+        name: String,
+        definition: ProjectDefinition,
+        default_target_app: &str,
+        default_task_params: &TaskParams,
+    ) -> Result<Self, PrismError> {
+        if name == "stream" {
+            // /criteria/stream would shadow /criteria/{project} for it
+            return Err(PrismError::ConfigError(
+                "A project can't be named stream".into(),
+            ));
+        }
+        let task_params = default_task_params.with(&definition.task);
+        task_params.validate().map_err(|e| {
+            PrismError::ConfigError(format!(
+                "Task parameters of project {name} are invalid: {e}"
+            ))
+        })?;
+        let mut site_task_params = BTreeMap::new();
+        for (site, overrides) in &definition.site_tasks {
+            let params = task_params.with(overrides);
+            params.validate().map_err(|e| {
+                PrismError::ConfigError(format!(
+                    "Task parameters of site {site} in project {name} are invalid: {e}"
+                ))
+            })?;
+            site_task_params.insert(site.clone(), params);
+        }
+
+        let mut query_definitions = definition.queries;
+        if query_definitions.is_empty() {
+            let body_file = definition
+                .body_file
+                .unwrap_or_else(|| PathBuf::from(format!("resources/body_{name}.json")));
+            let query_definition = QueryDefinition {
+                body_file,
+                refresh_interval: None,
+                ttl: None,
+            };
+            query_definitions.insert(DEFAULT_QUERY.to_string(), query_definition);
+        } else if definition.body_file.is_some() {
+            return Err(PrismError::ConfigError(format!(
+                "Project {name} has both a body file and named queries"
+            )));
+        }
+        let mut files = Vec::new();
+        let mut queries = BTreeMap::new();
+        for (query_name, query_definition) in query_definitions {
+            let query = NamedQuery {
+                name: query_name.clone(),
+                body: get_query(&query_definition.body_file)?,
+                refresh_interval: query_definition
+                    .refresh_interval
+                    .map_or(DEFAULT_REFRESH_INTERVAL, Duration::from_secs),
+                ttl: query_definition
+                    .ttl
+                    .map_or(CRITERIACACHE_TTL, Duration::from_secs),
+            };
+            files.push(query_definition.body_file);
+            queries.insert(query_name, Arc::new(query));
+        }
+        files.extend(definition.hierarchy_file.iter().cloned());
+        files.extend(definition.mapping_file.iter().cloned());
+        Ok(Project {
+            queries,
+            sites: definition.sites,
+            target_app: definition
+                .target_app
+                .unwrap_or_else(|| default_target_app.to_string()),
+            hierarchies: match &definition.hierarchy_file {
+                Some(hierarchy_file) => load_hierarchies(hierarchy_file)?,
+                None => Hierarchies::new(),
+            },
+            mapping: match &definition.mapping_file {
+                Some(mapping_file) => load_mapping(mapping_file)?,
+                None => Mapping::new(),
+            },
+            task_params,
+            site_task_params,
+            discovery: definition.discovery,
+            files,
+            name,
+        })
+    }
+
+    /// Identifies the body of a query and how its results are processed, cached criteria of the query with another fingerprint are outdated
+    pub fn fingerprint(&self, query: &str) -> String {
+        // criteria are normalized and rolled up before they are cached, so they depend on the mapping and hierarchies too
+        let parts = [
+            self.queries[query].body.clone().into_bytes(),
+            serde_json::to_vec(&self.mapping).expect("Failed to serialize JSON"),
+            serde_json::to_vec(&self.hierarchies).expect("Failed to serialize JSON"),
+        ];
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Parameters of the tasks to the site, those of the project unless overridden for the site
+    pub fn task_params(&self, site: &str) -> &TaskParams {
+        self.site_task_params.get(site).unwrap_or(&self.task_params)
+    }
+}
+
+pub fn load_project_definitions(
+    path: &Path,
+) -> Result<BTreeMap<String, ProjectDefinition>, PrismError> {
+    let projects = fs::read_to_string(path).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "Projects file {} can't be read: {e}",
+            path.display()
+        ))
+    })?;
+    serde_json::from_str(&projects).map_err(|e| {
+        PrismError::ConfigError(format!("Projects file {} is invalid: {e}", path.display()))
+    })
+}
+
+/// Reads the body file and checks it, so that a broken query is never sent to the sites. A readable AST is encoded the way Focus expects it.
+fn get_query(body_file: &Path) -> Result<String, PrismError> {
+    let body = fs::read_to_string(body_file).map_err(|e| {
+        PrismError::ConfigError(format!(
+            "Body file {} can't be read: {e}",
+            body_file.display()
+        ))
+    })?;
+    let (body, ast) = load_body(&body).map_err(|e| {
+        PrismError::ConfigError(format!("Body file {} is invalid: {e}", body_file.display()))
+    })?;
+    debug!("Query in {}:\n{}", body_file.display(), pretty_ast(&ast));
+    Ok(body)
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{cache::Site, queue_sites, SharedState};
+
+/// Sites Beam rejected as invalid receivers, they aren't queried again until their quarantine ends or they are released
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    entries: HashMap<Site, QuarantineEntry>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QuarantineEntry {
+    since: String, // RFC 3339
+    until: String,
+    reason: String,
+    #[serde(skip)]
+    expires: SystemTime,
+}
+
+impl Quarantine {
+    pub fn add(&mut self, site: Site, reason: String, duration: Duration) {
+        let now = SystemTime::now();
+        let expires = now + duration;
+        self.entries.insert(
+            site,
+            QuarantineEntry {
+                since: DateTime::<Utc>::from(now).to_rfc3339(),
+                until: DateTime::<Utc>::from(expires).to_rfc3339(),
+                reason,
+                expires,
+            },
+        );
+    }
+
+    pub fn contains(&self, site: &str) -> bool {
+        self.entries
+            .get(site)
+            .is_some_and(|entry| entry.expires > SystemTime::now())
+    }
+
+    /// Returns whether the site was quarantined
+    pub fn release(&mut self, site: &str) -> bool {
+        self.entries.remove(site).is_some()
+    }
+
+    /// Sites still in quarantine, those whose quarantine ended are forgotten
+    pub fn entries(&mut self) -> BTreeMap<Site, QuarantineEntry> {
+        let now = SystemTime::now();
+        self.entries.retain(|_, entry| entry.expires > now);
+        self.entries
+            .iter()
+            .map(|(site, entry)| (site.clone(), entry.clone()))
+            .collect()
+    }
+}
+
+/// Quarantined sites by project
+pub async fn handle_quarantine(
+    State(shared_state): State<SharedState>,
+) -> Json<BTreeMap<String, BTreeMap<Site, QuarantineEntry>>> {
+    let mut projects = BTreeMap::new();
+    for (name, project_state) in shared_state.projects.iter() {
+        let entries = project_state.quarantine.lock().await.entries();
+        projects.insert(name.clone(), entries);
+    }
+    Json(projects)
+}
+
+/// Releases a site from quarantine, it is queried again right away
+pub async fn handle_release(
+    State(shared_state): State<SharedState>,
+    Path((project, site)): Path<(String, Site)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let project_state = shared_state.project(Some(&project))?;
+    if !project_state.quarantine.lock().await.release(&site) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Site {site} is not quarantined in project {project}"),
+        ));
+    }
+    info!("Released site {site} of project {project} from quarantine");
+    if project_state.sites().contains(&site) {
+        for query_state in project_state.queries.values() {
+            queue_sites(query_state, vec![site.clone()]).await;
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quarantine() {
+        let mut quarantine = Quarantine::default();
+        quarantine.add(
+            "proxy1".into(),
+            "invalid receiver".into(),
+            Duration::from_secs(3600),
+        );
+        quarantine.add("proxy2".into(), "invalid receiver".into(), Duration::ZERO);
+        assert!(quarantine.contains("proxy1"));
+        assert!(!quarantine.contains("proxy2")); // already over
+        assert_eq!(
+            vec!["proxy1"],
+            quarantine.entries().keys().collect::<Vec<_>>()
+        );
+
+        assert!(quarantine.release("proxy1"));
+        assert!(!quarantine.release("proxy1"));
+        assert!(!quarantine.contains("proxy1"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::{self, CONFIG},
+    project::{Project, Projects},
+    queue_outdated_sites, SharedState,
+};
+
+/// Reloads the projects and CORS origins on SIGHUP and whenever one of their files changes, without dropping the cached criteria
+pub fn spawn_reloading(shared_state: SharedState) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut sighup = {
+            use tokio::signal::unix::{signal, SignalKind};
+            signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler")
+        };
+        let mut modified = modification_times(&shared_state);
+        loop {
+            #[cfg(unix)]
+            let hangup = sighup.recv();
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = hangup => info!("Received SIGHUP, reloading configuration"),
+                _ = sleep_or_forever(CONFIG.reload_interval) => {
+                    if modification_times(&shared_state) == modified {
+                        continue;
+                    }
+                    info!("Configuration files changed, reloading configuration");
+                }
+            }
+            reload(&shared_state).await;
+            modified = modification_times(&shared_state); // a failed reload is only tried again when the files change again
+        }
+    });
+}
+
+async fn sleep_or_forever(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn modification_times(shared_state: &SharedState) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    let mut files: Vec<PathBuf> = CONFIG
+        .projects_file
+        .iter()
+        .chain(CONFIG.cors_origin_file.iter())
+        .cloned()
+        .collect();
+    for project_state in shared_state.projects.values() {
+        files.extend(project_state.project().files.iter().cloned());
+    }
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = fs::metadata(&file).and_then(|m| m.modified()).ok();
+            (file, modified)
+        })
+        .collect()
+}
+
+async fn reload(shared_state: &SharedState) {
+    let (projects, cors_origins) = match config::reload() {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            warn!("Failed to reload the configuration, keeping the previous one: {e}");
+            return;
+        }
+    };
+    let previous_projects: Projects = shared_state
+        .projects
+        .iter()
+        .map(|(name, project_state)| (name.clone(), project_state.project()))
+        .collect();
+    if !same_queries(&previous_projects, &projects) {
+        // every project and query has its own cache and querying process, which are only set up at startup
+        warn!("Projects or queries were added or removed, this needs a restart. Keeping the previous configuration");
+        return;
+    }
+
+    for (name, project) in projects {
+        let project_state = &shared_state.projects[&name];
+        let previous = project_state.project();
+        if previous.sites != project.sites {
+            info!("Sites of project {name} changed to {:?}", project.sites);
+        }
+        let changed_queries = changed_queries(&previous, &project);
+        *project_state.project.write().unwrap() = project.clone();
+
+        for (query_name, query_state) in &project_state.queries {
+            if changed_queries.contains(&query_name) {
+                info!("Query {query_name} of project {name} or the processing of its results changed, invalidating its cached criteria");
+                query_state.criteria_cache.lock().await.cache.clear();
+                query_state.sites_in_flight.lock().await.clear(); // results of tasks with the previous query are discarded
+            }
+            queue_outdated_sites(query_state).await; // also queries sites added to the project
+        }
+    }
+
+    *shared_state.cors_origins.write().unwrap() = cors_origins;
+    info!("Configuration reloaded");
+}
+
+/// Whether the reloaded projects have the same names and queries, a reload can't add or remove any
+fn same_queries(previous: &Projects, reloaded: &Projects) -> bool {
+    previous.len() == reloaded.len()
+        && reloaded.iter().all(|(name, project)| {
+            previous
+                .get(name)
+                .is_some_and(|previous| previous.queries.keys().eq(project.queries.keys()))
+        })
+}
+
+/// Queries of a project whose cached criteria are outdated by the reload, because their body, or the processing of all the results, changed
+fn changed_queries<'a>(previous: &Project, reloaded: &'a Project) -> Vec<&'a String> {
+    reloaded
+        .queries
+        .keys()
+        .filter(|name| {
+            !previous.queries.contains_key(*name)
+                || previous.fingerprint(name) != reloaded.fingerprint(name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{hierarchy::Hierarchy, mapping::StratifierRule, test_config::project};
+
+    fn projects(projects: Vec<(&str, Project)>) -> Projects {
+        projects
+            .into_iter()
+            .map(|(name, project)| (name.to_string(), Arc::new(project)))
+            .collect()
+    }
+
+    #[test]
+    fn test_same_queries() {
+        let previous = projects(vec![
+            ("bbmri", project(&[("core", "a"), ("extra", "b")])),
+            ("dktk", project(&[("default", "c")])),
+        ]);
+        let changed_bodies = projects(vec![
+            ("bbmri", project(&[("core", "x"), ("extra", "y")])),
+            ("dktk", project(&[("default", "z")])),
+        ]);
+        assert!(same_queries(&previous, &changed_bodies));
+
+        let project_added = projects(vec![
+            ("bbmri", project(&[("core", "a"), ("extra", "b")])),
+            ("dktk", project(&[("default", "c")])),
+            ("gbn", project(&[("default", "d")])),
+        ]);
+        assert!(!same_queries(&previous, &project_added));
+        let project_removed = projects(vec![("bbmri", project(&[("core", "a"), ("extra", "b")]))]);
+        assert!(!same_queries(&previous, &project_removed));
+        let project_renamed = projects(vec![
+            ("bbmri", project(&[("core", "a"), ("extra", "b")])),
+            ("gbn", project(&[("default", "c")])),
+        ]);
+        assert!(!same_queries(&previous, &project_renamed));
+        let query_removed = projects(vec![
+            ("bbmri", project(&[("core", "a")])),
+            ("dktk", project(&[("default", "c")])),
+        ]);
+        assert!(!same_queries(&previous, &query_removed));
+    }
+
+    #[test]
+    fn test_changed_queries() {
+        let previous = project(&[("core", "a"), ("extra", "b")]);
+        assert!(changed_queries(&previous, &project(&[("core", "a"), ("extra", "b")])).is_empty());
+        assert_eq!(
+            vec!["extra"],
+            changed_queries(&previous, &project(&[("core", "a"), ("extra", "x")]))
+        );
+
+        let mut sites_changed = project(&[("core", "a"), ("extra", "b")]);
+        sites_changed.sites.push("proxy2".into());
+        assert!(changed_queries(&previous, &sites_changed).is_empty());
+
+        let mut mapping_changed = project(&[("core", "a"), ("extra", "b")]);
+        mapping_changed
+            .mapping
+            .insert("gender".into(), StratifierRule::default());
+        assert_eq!(
+            vec!["core", "extra"],
+            changed_queries(&previous, &mapping_changed)
+        );
+        let mut hierarchies_changed = project(&[("core", "a"), ("extra", "b")]);
+        hierarchies_changed
+            .hierarchies
+            .insert("diagnosis".into(), Hierarchy::default());
+        assert_eq!(
+            vec!["core", "extra"],
+            changed_queries(&previous, &hierarchies_changed)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    cache::{CriteriaCache, Site},
+    chunk::ChunkStatus,
+    outcome::SiteState,
+    QueryState, SharedState,
+};
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    beam_proxy: BeamProxyStatus,
+    querying: bool, // the sites are only queried once the Beam proxy was reachable, cached criteria are served before
+    projects: BTreeMap<String, ProjectReadiness>,
+}
+
+#[derive(Serialize, Debug)]
+struct ProjectReadiness {
+    sites_configured: usize, // including the discovered ones
+    sites_cached: usize, // configured sites with non-expired criteria in the cache for all the queries
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BeamProxyStatus {
+    Reachable,
+    Unreachable { error: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct SiteStatus {
+    last_updated: Option<String>, // RFC 3339, none if there are no criteria for the site in the cache
+    age_secs: Option<u64>,
+    ttl_secs: u64,
+    expired: bool,
+    queued: bool,      // waiting in sites_to_query for the next task to be sent
+    in_flight: bool,   // in a chunk waiting for a free slot or whose results are awaited
+    quarantined: bool, // rejected by Beam as an invalid receiver, not queried until released
+    state: SiteState,
+    last_attempt: Option<String>, // RFC 3339, when the last task was sent to the site
+    last_success: Option<String>,
+    consecutive_failures: u32,
+    last_error: Option<String>, // body of a failed result, or why there was none
+    retry_at: Option<String>,   // a failing site isn't queried again before
+}
+
+/// Liveness, Prism is alive as long as it answers
+pub async fn handle_health() -> &'static str {
+    "OK"
+}
+
+/// Readiness, Prism is ready when the beam proxy is reachable and it started querying the sites
+pub async fn handle_ready(
+    State(shared_state): State<SharedState>,
+) -> (StatusCode, Json<Readiness>) {
+    let beam_proxy = match shared_state.beam.check_proxy().await {
+        Ok(()) => BeamProxyStatus::Reachable,
+        Err(e) => BeamProxyStatus::Unreachable {
+            error: e.to_string(),
+        },
+    };
+
+    let mut projects = BTreeMap::new();
+    for (name, project_state) in shared_state.projects.iter() {
+        let sites = project_state.sites();
+        let mut cached_sites: Vec<&Site> = sites.iter().collect();
+        for query_state in project_state.queries.values() {
+            let criteria_cache = query_state.criteria_cache.lock().await;
+            cached_sites.retain(|site| {
+                criteria_cache.cache.get(*site).is_some_and(|(_, created)| {
+                    !CriteriaCache::is_expired(created, query_state.query().ttl)
+                })
+            });
+        }
+        projects.insert(
+            name.clone(),
+            ProjectReadiness {
+                sites_configured: sites.len(),
+                sites_cached: cached_sites.len(),
+            },
+        );
+    }
+
+    let querying = shared_state.querying.load(Ordering::Relaxed);
+    let status = match beam_proxy {
+        BeamProxyStatus::Reachable if querying => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        status,
+        Json(Readiness {
+            beam_proxy,
+            querying,
+            projects,
+        }),
+    )
+}
+
+/// Cache status of all the sites Prism knows about, those in its configuration, in the cache and waiting to be queried, by project and query
+pub async fn handle_sites(
+    State(shared_state): State<SharedState>,
+) -> Json<BTreeMap<String, BTreeMap<String, BTreeMap<Site, SiteStatus>>>> {
+    let mut projects = BTreeMap::new();
+    for (name, project_state) in shared_state.projects.iter() {
+        let mut queries = BTreeMap::new();
+        for (query, query_state) in &project_state.queries {
+            queries.insert(query.clone(), site_statuses(query_state).await);
+        }
+        projects.insert(name.clone(), queries);
+    }
+    Json(projects)
+}
+
+async fn site_statuses(query_state: &QueryState) -> BTreeMap<Site, SiteStatus> {
+    let criteria_cache = query_state.criteria_cache.lock().await;
+    let sites_to_query = query_state.sites_to_query.lock().await;
+    let sites_in_flight = query_state.sites_in_flight.lock().await;
+    let site_outcomes = query_state.site_outcomes.lock().await;
+    let quarantine = query_state.quarantine.lock().await;
+    let project_sites = query_state.sites();
+    let ttl = query_state.query().ttl;
+
+    let sites: BTreeSet<&Site> = project_sites
+        .iter()
+        .chain(criteria_cache.cache.keys())
+        .chain(sites_to_query.iter())
+        .chain(site_outcomes.keys())
+        .collect();
+
+    sites
+        .into_iter()
+        .map(|site| {
+            let created = criteria_cache.cache.get(site).map(|(_, created)| created);
+            let outcome = site_outcomes.get(site).cloned().unwrap_or_default();
+            let status = SiteStatus {
+                last_updated: created.map(rfc3339),
+                age_secs: created.map(|created| {
+                    SystemTime::now()
+                        .duration_since(*created)
+                        .unwrap_or_default()
+                        .as_secs()
+                }),
+                ttl_secs: ttl.as_secs(),
+                expired: created.is_none_or(|created| CriteriaCache::is_expired(created, ttl)),
+                queued: sites_to_query.contains(site),
+                in_flight: sites_in_flight
+                    .get(site)
+                    .is_some_and(|in_flight| in_flight.is_active()),
+                quarantined: quarantine.contains(site),
+                state: outcome.state,
+                last_attempt: outcome.last_attempt.as_ref().map(rfc3339),
+                last_success: outcome.last_success.as_ref().map(rfc3339),
+                consecutive_failures: outcome.consecutive_failures,
+                last_error: outcome.last_error,
+                retry_at: outcome.retry_at.as_ref().map(rfc3339),
+            };
+            (site.clone(), status)
+        })
+        .collect()
+}
+
+fn rfc3339(time: &SystemTime) -> String {
+    DateTime::<Utc>::from(*time).to_rfc3339()
+}
+
+/// Most recent chunks of sites tasks were sent to and what became of them, oldest first, by project and query
+pub async fn handle_tasks(
+    State(shared_state): State<SharedState>,
+) -> Json<BTreeMap<String, BTreeMap<String, Vec<ChunkStatus>>>> {
+    let mut projects = BTreeMap::new();
+    for (name, project_state) in shared_state.projects.iter() {
+        let mut queries = BTreeMap::new();
+        for (query, query_state) in &project_state.queries {
+            let chunks = query_state.chunks.lock().await;
+            queries.insert(query.clone(), chunks.iter().cloned().collect());
+        }
+        projects.insert(name.clone(), queries);
+    }
+    Json(projects)
+}
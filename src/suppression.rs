@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    criteria::{Criteria, Stratifiers},
+    hierarchy::Hierarchies,
+};
+
+/// Count of a stratum as returned to Lens, either the number or the marker of a suppressed count
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Count {
+    Exact(u64),
+    Suppressed(String),
+}
+
+pub type PublishedCriteria = BTreeMap<String, Count>;
+
+pub type PublishedStratifiers = BTreeMap<String, PublishedCriteria>;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SuppressionMode {
+    /// Suppressed counts are left out
+    Drop,
+    /// Suppressed counts are replaced with "<k"
+    Marker,
+}
+
+/// Suppresses counts below the threshold in every stratifier.
+/// If only one count of a stratifier is suppressed, it could be recovered by subtracting the others from the total, so the next smallest count is suppressed as well.
+/// The same goes for a parent code of a hierarchy and the children it is added up from.
+pub fn suppress(
+    stratifiers: Stratifiers,
+    threshold: Option<u64>,
+    mode: SuppressionMode,
+    hierarchies: &Hierarchies,
+) -> PublishedStratifiers {
+    stratifiers
+        .into_iter()
+        .map(|(key, criteria)| {
+            let published = match threshold {
+                Some(threshold) => {
+                    let families = hierarchies
+                        .get(&key)
+                        .map(|hierarchy| hierarchy.families(&criteria))
+                        .unwrap_or_default();
+                    suppress_criteria(criteria, threshold, mode, &families)
+                }
+                None => criteria
+                    .into_iter()
+                    .map(|(key, count)| (key, Count::Exact(count)))
+                    .collect(),
+            };
+            (key, published)
+        })
+        .collect()
+}
+
+fn suppress_criteria(
+    criteria: Criteria,
+    threshold: u64,
+    mode: SuppressionMode,
+    families: &[(String, Vec<String>)],
+) -> PublishedCriteria {
+    let mut suppressed: Vec<&String> = criteria
+        .iter()
+        .filter(|(_, &count)| count > 0 && count < threshold)
+        .map(|(key, _)| key)
+        .collect();
+
+    if suppressed.len() == 1 {
+        if let Some(key) = complement(&criteria, criteria.keys(), &suppressed) {
+            suppressed.push(key);
+        }
+    }
+
+    // a parent's count is the sum of its children's, so none of them may be the only one suppressed either
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (parent, children) in families {
+            let family = || std::iter::once(parent).chain(children);
+            if family().filter(|key| suppressed.contains(key)).count() == 1 {
+                if let Some(key) = complement(&criteria, family(), &suppressed) {
+                    suppressed.push(key);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let suppressed: Vec<String> = suppressed.into_iter().cloned().collect();
+
+    criteria
+        .into_iter()
+        .filter_map(|(key, count)| {
+            if !suppressed.contains(&key) {
+                Some((key, Count::Exact(count)))
+            } else {
+                match mode {
+                    SuppressionMode::Drop => None,
+                    SuppressionMode::Marker => {
+                        Some((key, Count::Suppressed(format!("<{threshold}"))))
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Smallest count to suppress in addition, not 0 as suppressing a 0 hides nothing
+fn complement<'a>(
+    criteria: &'a Criteria,
+    keys: impl Iterator<Item = &'a String>,
+    suppressed: &[&String],
+) -> Option<&'a String> {
+    keys.filter(|key| !suppressed.contains(key))
+        .filter_map(|key| criteria.get_key_value(key))
+        .filter(|(_, &count)| count > 0)
+        .min_by_key(|(_, &count)| count)
+        .map(|(key, _)| key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hierarchy::roll_up;
+
+    const HIERARCHY_ICD10: &str = include_str!("../resources/hierarchy_icd10.json");
+
+    fn stratifiers(criteria: &[(&str, u64)]) -> Stratifiers {
+        let criteria: Criteria = criteria
+            .iter()
+            .map(|(key, count)| (key.to_string(), *count))
+            .collect();
+        [("gender".into(), criteria)].into()
+    }
+
+    #[test]
+    fn test_no_threshold_keeps_everything() {
+        let published = suppress(
+            stratifiers(&[("female", 1), ("male", 20)]),
+            None,
+            SuppressionMode::Drop,
+            &Hierarchies::new(),
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":1,"male":20}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_complementary_suppression() {
+        // "other" alone could be recovered from the total, so "female" is suppressed too
+        let published = suppress(
+            stratifiers(&[("female", 15), ("male", 20), ("other", 2)]),
+            Some(5),
+            SuppressionMode::Marker,
+            &Hierarchies::new(),
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":"<5","male":20,"other":"<5"}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_no_complement_but_zeros() {
+        // suppressing a 0 as complement wouldn't hide the suppressed count, so there is no complement
+        let published = suppress(
+            stratifiers(&[("female", 0), ("male", 3), ("other", 0)]),
+            Some(5),
+            SuppressionMode::Marker,
+            &Hierarchies::new(),
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":0,"male":"<5","other":0}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+
+        let published = suppress(
+            stratifiers(&[("female", 0), ("male", 3), ("other", 40)]),
+            Some(5),
+            SuppressionMode::Marker,
+            &Hierarchies::new(),
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":0,"male":"<5","other":"<5"}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_drop_mode() {
+        let published = suppress(
+            stratifiers(&[("female", 15), ("male", 20), ("other", 2), ("unknown", 3)]),
+            Some(5),
+            SuppressionMode::Drop,
+            &Hierarchies::new(),
+        );
+        pretty_assertions::assert_eq!(
+            r#"{"gender":{"female":15,"male":20}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rolled_up_codes() {
+        // C02 and C02.1 would give away C02.0, C15-C26 the C16 chosen as complement, and C00-C97 and C00-C14 the C15-C26
+        let hierarchies: Hierarchies = serde_json::from_str(HIERARCHY_ICD10).unwrap();
+        let criteria: Criteria = [
+            ("C01".into(), 25),
+            ("C02.0".into(), 3),
+            ("C02.1".into(), 23),
+            ("C16".into(), 7),
+        ]
+        .into();
+        let stratifiers = roll_up([("diagnosis".into(), criteria)].into(), &hierarchies);
+        let published = suppress(stratifiers, Some(5), SuppressionMode::Marker, &hierarchies);
+        pretty_assertions::assert_eq!(
+            r#"{"diagnosis":{"C00-C14":"<5","C00-C97":58,"C00-D48":58,"C01":"<5","C02":26,"C02.0":"<5","C02.1":"<5","C15-C26":"<5","C16":"<5"}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+
+        // C15-C26 alone would be the difference of C00-C97 and C00-C14
+        let criteria: Criteria = [("C01".into(), 25), ("C16".into(), 2), ("C17".into(), 1)].into();
+        let stratifiers = roll_up([("diagnosis".into(), criteria)].into(), &hierarchies);
+        let published = suppress(stratifiers, Some(5), SuppressionMode::Marker, &hierarchies);
+        pretty_assertions::assert_eq!(
+            r#"{"diagnosis":{"C00-C14":"<5","C00-C97":28,"C00-D48":28,"C01":"<5","C15-C26":"<5","C16":"<5","C17":"<5"}}"#,
+            serde_json::to_string(&published).unwrap()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Configuration of the tests, which are run with the arguments of the test harness instead of Prism's
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    beam::TaskParams,
+    config::Config,
+    hierarchy::Hierarchies,
+    mapping::Mapping,
+    project::{NamedQuery, Project},
+};
+
+pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+    Config::from_args([
+        "prism",
+        "--beam-proxy-url",
+        "http://localhost:8081",
+        "--beam-app-id-long",
+        "prism.proxy0.broker",
+        "--api-key",
+        "test",
+        "--cors-origin",
+        "any",
+        "--project",
+        "bbmri",
+        "--sites",
+        "proxy1,proxy2,proxy3,proxy4,proxy5",
+        "--query-debounce",
+        "1",
+        "--result-deadline",
+        "2",
+        "--discovery-url",
+        "http://localhost:8082/v1/health/proxies",
+        "--discovery-deny",
+        "proxy0",
+    ])
+    .expect("Invalid test configuration")
+});
+
+/// Task parameters as given on the command line without any metadata
+pub fn task_params() -> TaskParams {
+    TaskParams {
+        ttl: Duration::from_secs(360),
+        retry_backoff: Duration::from_millis(1000),
+        max_tries: 5,
+        metadata: Default::default(),
+    }
+}
+
+/// Project bbmri with a single site and the named query bodies, without hierarchies or mapping
+pub fn project(queries: &[(&str, &str)]) -> Project {
+    Project {
+        name: "bbmri".into(),
+        sites: vec!["proxy1".into()],
+        queries: queries
+            .iter()
+            .map(|(name, body)| {
+                let query = NamedQuery {
+                    name: name.to_string(),
+                    body: body.to_string(),
+                    refresh_interval: Duration::from_secs(900),
+                    ttl: Duration::from_secs(7200),
+                };
+                (name.to_string(), Arc::new(query))
+            })
+            .collect(),
+        target_app: "focus".into(),
+        hierarchies: Hierarchies::new(),
+        mapping: Mapping::new(),
+        task_params: task_params(),
+        site_task_params: BTreeMap::new(),
+        discovery: false,
+        files: vec![],
+    }
+}
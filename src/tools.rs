@@ -0,0 +1,123 @@
+use std::{fs, path::Path, path::PathBuf, process::exit};
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{
+    body::{encode_ast, load_body, pretty_ast, yaml_ast, Ast},
+    config::CLAP_FOOTER,
+};
+
+const TOOLS: [&str; 2] = ["validate", "body"];
+
+/// Tools for working with Prism's configuration, run instead of the server
+#[derive(Parser, Debug)]
+#[clap(name("prism"), version, after_help(CLAP_FOOTER))]
+enum Tool {
+    /// Checks query body files and prints the AST they contain
+    Validate {
+        /// Body files to check, e.g. resources/body_bbmri.json
+        #[clap(required = true)]
+        body_files: Vec<PathBuf>,
+    },
+    /// Converts query bodies between the readable AST and the form sent to Focus
+    #[clap(subcommand)]
+    Body(BodyTool),
+}
+
+#[derive(Subcommand, Debug)]
+enum BodyTool {
+    /// Encodes an AST in readable JSON or YAML into a body with lang and payload
+    Encode {
+        /// File with the readable AST
+        input: PathBuf,
+        /// Where to write the body, printed if not given
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Decodes the AST from a body with lang and payload into readable JSON or YAML
+    Decode {
+        /// Body file, e.g. resources/body_bbmri.json
+        input: PathBuf,
+        /// Where to write the AST, printed if not given
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        #[clap(long, value_enum, default_value = "json")]
+        format: Format,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+/// Runs a tool and exits if the first argument names one
+pub fn run_if_requested() {
+    if !std::env::args()
+        .nth(1)
+        .is_some_and(|arg| TOOLS.contains(&arg.as_str()))
+    {
+        return;
+    }
+    let result = match Tool::parse() {
+        Tool::Validate { body_files } => validate(&body_files),
+        Tool::Body(BodyTool::Encode { input, output }) => {
+            read_body(&input).and_then(|(_, ast)| write(output.as_deref(), &encode_ast(&ast)))
+        }
+        Tool::Body(BodyTool::Decode {
+            input,
+            output,
+            format,
+        }) => read_body(&input).and_then(|(_, ast)| {
+            let readable = match format {
+                Format::Json => pretty_ast(&ast),
+                Format::Yaml => yaml_ast(&ast),
+            };
+            write(output.as_deref(), &readable)
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        exit(1);
+    }
+    exit(0);
+}
+
+fn validate(body_files: &[PathBuf]) -> Result<(), String> {
+    let mut invalid = 0;
+    for body_file in body_files {
+        match read_body(body_file) {
+            Ok((_, ast)) => println!("{} is valid:\n{}", body_file.display(), pretty_ast(&ast)),
+            Err(e) => {
+                eprintln!("{e}");
+                invalid += 1;
+            }
+        }
+    }
+    match invalid {
+        0 => Ok(()),
+        invalid => Err(format!(
+            "{invalid} of {} body files are invalid",
+            body_files.len()
+        )),
+    }
+}
+
+fn read_body(body_file: &Path) -> Result<(String, Ast), String> {
+    fs::read_to_string(body_file)
+        .map_err(|e| format!("can't be read, {e}"))
+        .and_then(|body| load_body(&body).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{} is invalid: {e}", body_file.display()))
+}
+
+fn write(output: Option<&Path>, content: &str) -> Result<(), String> {
+    match output {
+        Some(output) => fs::write(output, content)
+            .map_err(|e| format!("{} can't be written: {e}", output.display())),
+        None => {
+            println!("{content}");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::{io, time::Duration};
+
+use beam_lib::{BeamClient, MsgId, RawString, TaskRequest, TaskResult};
+use futures_util::{future::BoxFuture, stream::BoxStream, StreamExt as _, TryStreamExt as _};
+use reqwest::{header, header::HeaderValue, Method};
+
+use crate::{config::CONFIG, errors::PrismError};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10); // for the requests that are answered right away, not the long ones waiting for results
+
+/// A result read from Beam, or a message that isn't one
+#[derive(Debug)]
+pub enum ResultEvent {
+    Result(TaskResult<RawString>),
+    Malformed(String),
+}
+
+pub type ResultStream = BoxStream<'static, Result<ResultEvent, PrismError>>; // ends with an error if the connection breaks off
+
+/// How Prism talks to Beam, the Beam proxy or a fake in tests
+pub trait BeamTransport: Send + Sync {
+    fn post_task<'a>(
+        &'a self,
+        task: &'a TaskRequest<RawString>,
+    ) -> BoxFuture<'a, beam_lib::Result<()>>;
+
+    /// Results of a task as they arrive, until wait_count results are there
+    fn stream_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+    ) -> BoxFuture<'_, Result<ResultStream, PrismError>>;
+
+    /// Results of a task, once wait_count results are there or wait_time passed
+    fn poll_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+        wait_time: Duration,
+    ) -> BoxFuture<'_, Result<Vec<ResultEvent>, PrismError>>;
+
+    /// Asks the Beam proxy once whether it is healthy
+    fn check_proxy(&self) -> BoxFuture<'_, beam_lib::Result<()>>;
+
+    /// Ids of the proxies connected to the Beam broker, e.g. proxy1.broker.samply.de
+    fn list_proxies(&self) -> BoxFuture<'_, Result<Vec<String>, PrismError>>;
+}
+
+/// The Beam proxy configured on the command line
+pub struct BeamProxy {
+    client: BeamClient,
+    http: reqwest::Client, // for the health check and discovery, which aren't Beam API calls
+}
+
+impl BeamProxy {
+    pub fn new() -> Self {
+        BeamProxy {
+            client: BeamClient::new(
+                &CONFIG.beam_app_id_long,
+                &CONFIG.api_key,
+                CONFIG.beam_proxy_url.clone(),
+            ),
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build the HTTP client"),
+        }
+    }
+}
+
+impl BeamTransport for BeamProxy {
+    fn post_task<'a>(
+        &'a self,
+        task: &'a TaskRequest<RawString>,
+    ) -> BoxFuture<'a, beam_lib::Result<()>> {
+        Box::pin(self.client.post_task(task))
+    }
+
+    fn stream_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+    ) -> BoxFuture<'_, Result<ResultStream, PrismError>> {
+        Box::pin(async move {
+            let resp = self
+                .client
+                .raw_beam_request(
+                    Method::GET,
+                    &format!("v1/tasks/{}/results?wait_count={}", task_id, wait_count),
+                )
+                .header(
+                    header::ACCEPT,
+                    HeaderValue::from_static("text/event-stream"),
+                )
+                .send()
+                .await
+                .map_err(|e| PrismError::BeamError(e.to_string()))?;
+
+            let code = resp.status();
+            if !code.is_success() {
+                return Err(PrismError::BeamError(
+                    resp.text().await.unwrap_or_else(|e| e.to_string()),
+                ));
+            }
+            let is_event_stream = resp
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_some_and(|content_type| content_type.starts_with("text/event-stream"));
+            if !is_event_stream {
+                return Err(PrismError::BeamError(
+                    "No event stream in the answer, a proxy in between may strip it".into(),
+                ));
+            }
+            let stream = async_sse::decode(
+                resp.bytes_stream()
+                    .map_err(io::Error::other)
+                    .into_async_read(),
+            );
+            Ok(stream
+                .filter_map(|event| async move {
+                    match event {
+                        Ok(async_sse::Event::Message(msg)) => Some(Ok(parse_result(msg.data()))),
+                        Ok(async_sse::Event::Retry(_)) => None,
+                        Err(e) => Some(Err(PrismError::BeamError(format!(
+                            "Result stream broke off: {e}"
+                        )))),
+                    }
+                })
+                .boxed())
+        })
+    }
+
+    fn poll_results(
+        &self,
+        task_id: MsgId,
+        wait_count: usize,
+        wait_time: Duration,
+    ) -> BoxFuture<'_, Result<Vec<ResultEvent>, PrismError>> {
+        Box::pin(async move {
+            let resp = self
+                .client
+                .raw_beam_request(
+                    Method::GET,
+                    &format!(
+                        "v1/tasks/{}/results?wait_count={}&wait_time={}ms",
+                        task_id,
+                        wait_count,
+                        wait_time.as_millis()
+                    ),
+                )
+                .header(header::ACCEPT, HeaderValue::from_static("application/json"))
+                .send()
+                .await
+                .map_err(|e| PrismError::BeamError(e.to_string()))?;
+
+            let code = resp.status();
+            if !code.is_success() {
+                // 206 Partial Content if wait_time passed first is a success too
+                return Err(PrismError::BeamError(
+                    resp.text().await.unwrap_or_else(|e| e.to_string()),
+                ));
+            }
+            let results: Vec<serde_json::Value> = resp.json().await.map_err(|e| {
+                PrismError::BeamError(format!("Failed to deserialize the results: {e}"))
+            })?;
+            Ok(results
+                .into_iter()
+                .map(|result| match serde_json::from_value(result.clone()) {
+                    Ok(result) => ResultEvent::Result(result),
+                    Err(e) => ResultEvent::Malformed(format!("{e}: {result}")),
+                })
+                .collect())
+        })
+    }
+
+    fn check_proxy(&self) -> BoxFuture<'_, beam_lib::Result<()>> {
+        Box::pin(async {
+            let res = self
+                .http
+                .get(format!("{}v1/health", CONFIG.beam_proxy_url)) //FIXME why doesn't it work with url from config
+                .send()
+                .await?;
+            if res.status() == reqwest::StatusCode::OK {
+                Ok(())
+            } else {
+                Err(beam_lib::BeamError::Other(
+                    format!("Proxy reachable but failed to start {}", res.status()).into(),
+                ))
+            }
+        })
+    }
+
+    fn list_proxies(&self) -> BoxFuture<'_, Result<Vec<String>, PrismError>> {
+        Box::pin(async {
+            let url = CONFIG
+                .discovery_url
+                .clone()
+                .ok_or_else(|| PrismError::ConfigError("No discovery URL configured".into()))?;
+            let mut request = self.http.get(url);
+            if let Some(api_key) = &CONFIG.discovery_api_key {
+                request = request.basic_auth("", Some(api_key));
+            }
+            let resp = request
+                .send()
+                .await
+                .map_err(|e| PrismError::BeamError(e.to_string()))?;
+            let code = resp.status();
+            if !code.is_success() {
+                return Err(PrismError::BeamError(format!(
+                    "Listing the connected proxies failed with {code}: {}",
+                    resp.text().await.unwrap_or_else(|e| e.to_string())
+                )));
+            }
+            resp.json().await.map_err(|e| {
+                PrismError::BeamError(format!("Failed to deserialize the connected proxies: {e}"))
+            })
+        })
+    }
+}
+
+fn parse_result(data: &[u8]) -> ResultEvent {
+    match serde_json::from_slice(data) {
+        Ok(result) => ResultEvent::Result(result),
+        Err(e) => ResultEvent::Malformed(format!("{e}: {}", String::from_utf8_lossy(data))),
+    }
+}